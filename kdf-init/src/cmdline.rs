@@ -3,17 +3,46 @@
 use anyhow::{Context, Result};
 use std::collections::HashMap;
 
+/// A single lower layer that can be stacked into an overlayfs mount
+#[derive(Debug, Clone, PartialEq)]
+pub enum LowerLayer {
+    /// A virtiofs share, identified by its tag
+    Virtiofs(String),
+    /// A squashfs image file, loop-mounted read-only before being stacked
+    Squashfs(String),
+}
+
 /// Virtiofs mount specification
 #[derive(Debug, Clone, PartialEq)]
 pub struct VirtiofsMount {
-    /// Virtiofs tag to mount
-    pub tag: String,
+    /// Ordered lower layers to stack, highest priority first (matches overlayfs' `lowerdir=`
+    /// ordering). A single `Virtiofs` layer is the common case of mounting one share directly.
+    pub layers: Vec<LowerLayer>,
     /// Path to mount at
     pub path: String,
     /// Whether to create overlayfs with writable layer
     pub with_overlay: bool,
 }
 
+/// 9p shared-folder mount specification
+#[derive(Debug, Clone, PartialEq)]
+pub struct NinePMount {
+    /// 9p export tag
+    pub tag: String,
+    /// Path to mount at
+    pub path: String,
+    /// Transport, e.g. "virtio" (passed as `trans=`)
+    pub trans: Option<String>,
+    /// Protocol version, e.g. "9p2000.L" (passed as `version=`)
+    pub version: Option<String>,
+    /// Maximum packet size (passed as `msize=`)
+    pub msize: Option<u32>,
+    /// Cache mode, e.g. "loose"/"none" (passed as `cache=`)
+    pub cache: Option<String>,
+    /// Whether to create overlayfs with writable layer on top
+    pub with_overlay: bool,
+}
+
 /// Symlink specification
 #[derive(Debug, Clone, PartialEq)]
 pub struct Symlink {
@@ -23,12 +52,108 @@ pub struct Symlink {
     pub target: String,
 }
 
-/// Parse init.shell value by splitting on whitespace
+/// Real-root handoff: mount a persistent root filesystem and switch_root into it instead of
+/// terminating in the initramfs shell
+#[derive(Debug, Clone, PartialEq)]
+pub struct SwitchRoot {
+    /// Block device (or other mount source) holding the real root filesystem
+    pub device: String,
+    /// Filesystem type to mount `device` as (e.g. "ext4"); if unset, common types are tried
+    pub fstype: Option<String>,
+    /// Raw mount options string (comma separated, fstab-style), e.g. "ro,noatime"
+    pub flags: String,
+    /// Init program to exec inside the new root once switch_root completes
+    pub realinit: String,
+}
+
+/// Unprivileged user to launch the shell/script as, instead of root
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserSpec {
+    /// Username, used only to populate USER/LOGNAME (no passwd lookup is performed)
+    pub user: Option<String>,
+    /// uid to setuid to
+    pub uid: u32,
+    /// gid to setgid to (defaults to `uid` if not given)
+    pub gid: u32,
+    /// Supplementary group ids to pass to setgroups
+    pub groups: Vec<u32>,
+    /// HOME to export (defaults to "/root" for uid 0, otherwise "/home/<uid>")
+    pub home: String,
+}
+
+/// Tokenize a command string the way a POSIX-ish shell would, so `init.shell`/`init.script`
+/// values can carry quoted arguments instead of being mangled by a plain whitespace split.
+///
+/// - Unquoted whitespace (space/tab) ends the current token.
+/// - `'...'` is taken completely literally, including whitespace and backslashes.
+/// - `"..."` groups a token but still honors `\"` and `\\`.
+/// - A backslash outside single quotes escapes the next character.
+/// - A token is only emitted if it contained characters or quotes, so `''` yields `""`.
+///
+/// Example: "sh -i" -> ["sh", "-i"]
+/// Example: `sh -c "echo hi"` -> ["sh", "-c", "echo hi"]
+pub(crate) fn tokenize(value: &str) -> Result<Vec<String>> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut in_token = false;
+    let mut in_single_quote = false;
+    let mut in_double_quote = false;
+    let mut escaped = false;
+
+    for ch in value.chars() {
+        if escaped {
+            current.push(ch);
+            escaped = false;
+            in_token = true;
+            continue;
+        }
+
+        match ch {
+            '\\' if !in_single_quote => {
+                escaped = true;
+                in_token = true;
+            }
+            '\'' if !in_double_quote => {
+                in_single_quote = !in_single_quote;
+                in_token = true;
+            }
+            '"' if !in_single_quote => {
+                in_double_quote = !in_double_quote;
+                in_token = true;
+            }
+            ' ' | '\t' if !in_single_quote && !in_double_quote => {
+                if in_token {
+                    tokens.push(std::mem::take(&mut current));
+                    in_token = false;
+                }
+            }
+            _ => {
+                current.push(ch);
+                in_token = true;
+            }
+        }
+    }
+
+    if in_single_quote || in_double_quote {
+        anyhow::bail!("Unterminated quote in command: {}", value);
+    }
+    if escaped {
+        anyhow::bail!("Unterminated escape in command: {}", value);
+    }
+    if in_token {
+        tokens.push(current);
+    }
+
+    Ok(tokens)
+}
+
+/// Parse an `init.shell`/`init.script` value into (program, args) using the quote-aware
+/// tokenizer above.
 ///
 /// Example: "sh -i" -> ("sh", vec!["-i"])
 /// Example: "sh" -> ("sh", vec![])
-fn parse_shell_command(value: &str) -> Result<(String, Vec<String>)> {
-    let mut parts: Vec<String> = value.split_whitespace().map(|s| s.to_string()).collect();
+pub(crate) fn parse_shell_command(value: &str) -> Result<(String, Vec<String>)> {
+    let mut parts = tokenize(value)?;
     if parts.is_empty() {
         anyhow::bail!("Shell command is empty");
     }
@@ -49,84 +174,558 @@ fn parse_backtick_command(value: &str) -> Result<String> {
     Ok(value[1..value.len() - 1].to_string())
 }
 
+/// How one pipeline in a [`Script`] relates to the pipeline before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PipelineJoin {
+    /// `;` - run regardless of the previous pipeline's exit status
+    Sequential,
+    /// `&&` - run only if the previous pipeline succeeded
+    And,
+    /// `||` - run only if the previous pipeline failed
+    Or,
+}
+
+/// A file redirection attached to a command's stdout.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Redirect {
+    /// `>file` - truncate and write
+    Truncate(String),
+    /// `>>file` - append
+    Append(String),
+}
+
+/// A single command within a [`Pipeline`]: program, args, and optional redirections.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Command {
+    pub program: String,
+    pub args: Vec<String>,
+    /// `<file` - read stdin from this file instead of the previous command's stdout
+    pub stdin: Option<String>,
+    pub stdout: Option<Redirect>,
+}
+
+/// A `|`-separated chain of commands, each command's stdout feeding the next one's stdin.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pipeline {
+    pub commands: Vec<Command>,
+}
+
+/// A parsed `init.script` value: a sequence of pipelines joined by `;`/`&&`/`||`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Script {
+    /// Each pipeline paired with how it joins to the pipeline before it (the first entry's
+    /// join is meaningless, since there is no previous pipeline, but is always `Sequential`).
+    pub pipelines: Vec<(PipelineJoin, Pipeline)>,
+}
+
+/// Token produced by [`tokenize_script`]: either a word (to become a program/arg/redirect
+/// target) or one of the script's structural operators.
+#[derive(Debug, Clone, PartialEq)]
+enum ScriptToken {
+    Word(String),
+    Semi,
+    And,
+    Or,
+    Pipe,
+    RedirectIn,
+    RedirectOut,
+    RedirectAppend,
+}
+
+/// Push the in-progress word onto `tokens` if one is pending, same emission rule as [`tokenize`].
+fn flush_word(current: &mut String, in_token: &mut bool, tokens: &mut Vec<ScriptToken>) {
+    if *in_token {
+        tokens.push(ScriptToken::Word(std::mem::take(current)));
+        *in_token = false;
+    }
+}
+
+/// Tokenize an `init.script` value into words and `;`/`&&`/`||`/`|`/`<`/`>`/`>>` operators,
+/// using the same quote/escape rules as [`tokenize`] (operators inside quotes are literal).
+fn tokenize_script(value: &str) -> Result<Vec<ScriptToken>> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut in_token = false;
+    let mut in_single_quote = false;
+    let mut in_double_quote = false;
+    let mut escaped = false;
+    let mut chars = value.chars().peekable();
+
+    while let Some(ch) = chars.next() {
+        if escaped {
+            current.push(ch);
+            escaped = false;
+            in_token = true;
+            continue;
+        }
+
+        match ch {
+            '\\' if !in_single_quote => {
+                escaped = true;
+                in_token = true;
+            }
+            '\'' if !in_double_quote => {
+                in_single_quote = !in_single_quote;
+                in_token = true;
+            }
+            '"' if !in_single_quote => {
+                in_double_quote = !in_double_quote;
+                in_token = true;
+            }
+            ' ' | '\t' if !in_single_quote && !in_double_quote => {
+                flush_word(&mut current, &mut in_token, &mut tokens);
+            }
+            ';' if !in_single_quote && !in_double_quote => {
+                flush_word(&mut current, &mut in_token, &mut tokens);
+                tokens.push(ScriptToken::Semi);
+            }
+            '&' if !in_single_quote && !in_double_quote => {
+                flush_word(&mut current, &mut in_token, &mut tokens);
+                if chars.peek() == Some(&'&') {
+                    chars.next();
+                    tokens.push(ScriptToken::And);
+                } else {
+                    anyhow::bail!("Unsupported '&' in script (use ';' or '&&'): {}", value);
+                }
+            }
+            '|' if !in_single_quote && !in_double_quote => {
+                flush_word(&mut current, &mut in_token, &mut tokens);
+                if chars.peek() == Some(&'|') {
+                    chars.next();
+                    tokens.push(ScriptToken::Or);
+                } else {
+                    tokens.push(ScriptToken::Pipe);
+                }
+            }
+            '<' if !in_single_quote && !in_double_quote => {
+                flush_word(&mut current, &mut in_token, &mut tokens);
+                tokens.push(ScriptToken::RedirectIn);
+            }
+            '>' if !in_single_quote && !in_double_quote => {
+                flush_word(&mut current, &mut in_token, &mut tokens);
+                if chars.peek() == Some(&'>') {
+                    chars.next();
+                    tokens.push(ScriptToken::RedirectAppend);
+                } else {
+                    tokens.push(ScriptToken::RedirectOut);
+                }
+            }
+            _ => {
+                current.push(ch);
+                in_token = true;
+            }
+        }
+    }
+
+    if in_single_quote || in_double_quote {
+        anyhow::bail!("Unterminated quote in script: {}", value);
+    }
+    if escaped {
+        anyhow::bail!("Unterminated escape in script: {}", value);
+    }
+    flush_word(&mut current, &mut in_token, &mut tokens);
+
+    Ok(tokens)
+}
+
+/// Parse an `init.script` value into a [`Script`]: pipelines of `|`-chained commands joined by
+/// `;`/`&&`/`||`, with optional `<file`/`>file`/`>>file` redirections per command.
+///
+/// Example: `mount x | grep y && touch /ready` -> two pipelines, the second gated on the first
+/// succeeding, where the first pipeline is itself two piped commands.
+pub(crate) fn parse_script(value: &str) -> Result<Script> {
+    let tokens = tokenize_script(value)?;
+
+    let mut pipelines = Vec::new();
+    let mut join = PipelineJoin::Sequential;
+    let mut commands = Vec::new();
+    let mut command = Command::default();
+    let mut has_command = false;
+    let mut pending_redirect: Option<ScriptToken> = None;
+
+    let finish_command = |commands: &mut Vec<Command>,
+                           command: &mut Command,
+                           has_command: &mut bool|
+     -> Result<()> {
+        if !*has_command {
+            anyhow::bail!("Empty command in script: {}", value);
+        }
+        commands.push(std::mem::take(command));
+        *has_command = false;
+        Ok(())
+    };
+
+    for token in tokens {
+        if let Some(redirect) = pending_redirect.take() {
+            let ScriptToken::Word(target) = token else {
+                anyhow::bail!("Redirect in script must be followed by a filename: {}", value);
+            };
+            match redirect {
+                ScriptToken::RedirectIn => command.stdin = Some(target),
+                ScriptToken::RedirectOut => command.stdout = Some(Redirect::Truncate(target)),
+                ScriptToken::RedirectAppend => command.stdout = Some(Redirect::Append(target)),
+                _ => unreachable!(),
+            }
+            continue;
+        }
+
+        match token {
+            ScriptToken::Word(word) => {
+                if !has_command {
+                    command.program = word;
+                    has_command = true;
+                } else {
+                    command.args.push(word);
+                }
+            }
+            ScriptToken::RedirectIn | ScriptToken::RedirectOut | ScriptToken::RedirectAppend => {
+                pending_redirect = Some(token);
+            }
+            ScriptToken::Pipe => {
+                finish_command(&mut commands, &mut command, &mut has_command)?;
+            }
+            ScriptToken::Semi | ScriptToken::And | ScriptToken::Or => {
+                finish_command(&mut commands, &mut command, &mut has_command)?;
+                let pipeline = Pipeline { commands: std::mem::take(&mut commands) };
+                check_pipeline_redirects(&pipeline, value)?;
+                pipelines.push((join, pipeline));
+                join = match token {
+                    ScriptToken::And => PipelineJoin::And,
+                    ScriptToken::Or => PipelineJoin::Or,
+                    _ => PipelineJoin::Sequential,
+                };
+            }
+        }
+    }
+
+    if pending_redirect.is_some() {
+        anyhow::bail!("Redirect in script must be followed by a filename: {}", value);
+    }
+    finish_command(&mut commands, &mut command, &mut has_command)?;
+    let pipeline = Pipeline { commands };
+    check_pipeline_redirects(&pipeline, value)?;
+    pipelines.push((join, pipeline));
+
+    Ok(Script { pipelines })
+}
+
+/// `run_pipeline` only wires stdin from the previous command / stdout into the next command for
+/// commands in the middle of a pipe chain, so a `<file`/`>file` parsed onto one of those would
+/// silently never take effect. Reject that at parse time instead: only the first command in a
+/// pipeline may redirect stdin, and only the last may redirect stdout.
+fn check_pipeline_redirects(pipeline: &Pipeline, value: &str) -> Result<()> {
+    let last = pipeline.commands.len().saturating_sub(1);
+    for (i, cmd) in pipeline.commands.iter().enumerate() {
+        if i != 0 && cmd.stdin.is_some() {
+            anyhow::bail!(
+                "Redirect '<' only allowed on the first command of a pipeline: {}",
+                value
+            );
+        }
+        if i != last && cmd.stdout.is_some() {
+            anyhow::bail!(
+                "Redirect '>'/'>>' only allowed on the last command of a pipeline: {}",
+                value
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Maximum alias expansion chain length before `expand_alias` assumes a cycle and bails.
+const MAX_ALIAS_DEPTH: usize = 16;
+
+/// Expand `command`'s program if it names an entry in `aliases`, substituting the alias's
+/// `(program, args)` with the command's own args appended after the alias's. Keeps expanding
+/// while the result's program is itself an alias, guarding against alias -> alias cycles with a
+/// visited set and a `MAX_ALIAS_DEPTH` cap.
+///
+/// Example: alias `ready` -> `touch /run/ready`, command `ready --verbose` -> `touch /run/ready
+/// --verbose`.
+pub(crate) fn expand_alias(
+    command: (String, Vec<String>),
+    aliases: &HashMap<String, (String, Vec<String>)>,
+) -> Result<(String, Vec<String>)> {
+    let (mut program, mut args) = command;
+    let mut visited = std::collections::HashSet::new();
+
+    for _ in 0..MAX_ALIAS_DEPTH {
+        let Some((alias_program, alias_args)) = aliases.get(&program) else {
+            return Ok((program, args));
+        };
+        if !visited.insert(program.clone()) {
+            anyhow::bail!("Alias loop detected while expanding '{}'", program);
+        }
+
+        let mut expanded_args = alias_args.clone();
+        expanded_args.extend(args);
+        program = alias_program.clone();
+        args = expanded_args;
+    }
+
+    anyhow::bail!(
+        "Alias expansion exceeded {} levels (possible cycle) at '{}'",
+        MAX_ALIAS_DEPTH,
+        program
+    )
+}
+
+/// Apply [`expand_alias`] to every command in every pipeline of `script`, in place.
+pub(crate) fn expand_script_aliases(
+    script: &mut Script,
+    aliases: &HashMap<String, (String, Vec<String>)>,
+) -> Result<()> {
+    for (_, pipeline) in &mut script.pipelines {
+        for command in &mut pipeline.commands {
+            let expanded = expand_alias(
+                (std::mem::take(&mut command.program), std::mem::take(&mut command.args)),
+                aliases,
+            )?;
+            command.program = expanded.0;
+            command.args = expanded.1;
+        }
+    }
+
+    Ok(())
+}
+
 /// Parsed init configuration from kernel cmdline
 #[derive(Debug, PartialEq)]
 pub struct Config {
     /// Virtiofs mounts to create
     pub virtiofs_mounts: Vec<VirtiofsMount>,
+    /// 9p shared-folder mounts to create
+    pub ninep_mounts: Vec<NinePMount>,
     /// Symlinks to create
     pub symlinks: Vec<Symlink>,
     /// Environment variables to set
     pub env_vars: HashMap<String, String>,
+    /// Dotenv-style files to load `KEY=VALUE` pairs from, in order, after mounts are set up (see
+    /// `parse_envfile`). `env_vars` entries from the cmdline take precedence over these, and
+    /// later files override earlier ones.
+    pub envfiles: Vec<String>,
     /// Shell program and args - required (program, args)
     pub shell: (String, Vec<String>),
-    /// Optional script to execute (not yet implemented)
-    pub script: Option<String>,
+    /// Optional script to run before the shell: pipelines of commands joined by `;`/`&&`/`||`
+    pub script: Option<Script>,
+    /// Optional provisioning script file, one step per line (see `seq::parse_script_file`)
+    pub script_file: Option<String>,
     /// Directory to load kernel modules from (if None, no modules loaded)
     pub moddir: Option<String>,
     /// Console device to use - required
     pub console: String,
     /// Optional directory to change to before spawning shell
     pub chdir: Option<String>,
+    /// Optional unprivileged user to drop to before exec'ing the shell/script
+    pub user: Option<UserSpec>,
+    /// Per-module parameters, keyed by module basename (e.g. "e1000e" for "e1000e.ko.xz"),
+    /// passed through to `finit_module` instead of an empty parameter string
+    pub module_params: HashMap<String, String>,
+    /// Optional switch_root handoff into a persistent root filesystem
+    pub switch_root: Option<SwitchRoot>,
+    /// Command aliases, keyed by name: when a shell/script command's program matches a key
+    /// here, it's expanded to the aliased (program, args) with the original args appended
+    /// (see `expand_alias`)
+    pub aliases: HashMap<String, (String, Vec<String>)>,
+    /// Optional path for a Unix control socket accepting runtime command requests (see
+    /// `control::spawn_listener`)
+    pub control: Option<String>,
 }
 
 /// Parse kernel cmdline into Config
 ///
-/// Supports: init.virtiofs, init.symlinks, init.env.XXX, init.shell, init.script, init.moddir, init.console, init.chdir
-/// init.shell and init.script values must be wrapped in backticks
+/// Supports: init.virtiofs, init.symlinks, init.env.XXX, init.shell, init.script, init.moddir,
+/// init.console, init.chdir, init.user, init.uid, init.gid, init.groups, init.home,
+/// init.modparam.NAME (backtick-wrapped, like init.shell), init.alias.NAME (backtick-wrapped and
+/// tokenized, like init.shell), init.root, init.rootfstype,
+/// init.rootflags, init.realinit, init.script.file, init.9p, init.envfile, init.control
+/// init.shell and init.script values must be wrapped in backticks and are tokenized the same
+/// quote/escape-aware way (see [`parse_shell_command`]); an empty init.script value is treated
+/// as "no script"
 /// init.shell is required, init.script is optional
 /// init.console is required
+/// init.uid/init.gid/init.groups/init.home/init.user are all optional; when none of them are
+/// given the shell/script is launched as root (today's behavior)
+///
+/// Since cmdline parameter order isn't guaranteed, `init.env.*` is collected in a first pass over
+/// the parameters; a second pass then applies [`interpolate`] (`${VAR}`/`$VAR`/`${VAR:-default}`)
+/// against that map to init.virtiofs/init.9p/init.symlinks/init.chdir/init.moddir and the
+/// unwrapped init.shell/init.script command strings before they're tokenized. init.envfile values
+/// aren't available here: envfiles may themselves live on the mounts being interpolated, so they
+/// can only be loaded (and merged into `env_vars`) afterwards, once mounts are up (see
+/// `main::run`).
 pub fn parse_cmdline(cmdline: &str) -> Result<Config> {
+    // Parse parameters respecting backtick-enclosed values
+    let params = parse_cmdline_params(cmdline);
+
+    // First pass: collect init.env.* only, so interpolation below can see every env var
+    // regardless of where on the cmdline it appears relative to the values that reference it.
+    let mut env_vars = HashMap::new();
+    for param in &params {
+        if let Some(rest) = param.strip_prefix("init.env.") {
+            if let Some((key, value)) = rest.split_once('=') {
+                env_vars.insert(key.to_string(), value.to_string());
+            }
+        }
+    }
+
     let mut virtiofs_mounts = Vec::new();
+    let mut ninep_mounts = Vec::new();
     let mut symlinks = Vec::new();
-    let mut env_vars = HashMap::new();
+    let mut envfiles = Vec::new();
     let mut shell = None;
     let mut script = None;
+    let mut script_file = None;
     let mut moddir = None;
     let mut console = None;
     let mut chdir = None;
-
-    // Parse parameters respecting backtick-enclosed values
-    let params = parse_cmdline_params(cmdline);
-
+    let mut user = None;
+    let mut uid = None;
+    let mut gid = None;
+    let mut groups = None;
+    let mut home = None;
+    let mut module_params = HashMap::new();
+    let mut aliases = HashMap::new();
+    let mut root_device = None;
+    let mut root_fstype = None;
+    let mut root_flags = None;
+    let mut realinit = None;
+    let mut control = None;
+
+    // Second pass: parse every other parameter, interpolating init.env.* references first
     for param in params {
         if let Some(value) = param.strip_prefix("init.virtiofs=") {
-            virtiofs_mounts = parse_virtiofs_mounts(value)?;
+            virtiofs_mounts = parse_virtiofs_mounts(&interpolate(value, &env_vars)?)?;
+        } else if let Some(value) = param.strip_prefix("init.9p=") {
+            ninep_mounts = parse_9p_mounts(&interpolate(value, &env_vars)?)?;
         } else if let Some(value) = param.strip_prefix("init.symlinks=") {
-            symlinks = parse_symlinks(value)?;
-        } else if let Some(rest) = param.strip_prefix("init.env.") {
-            if let Some((key, value)) = rest.split_once('=') {
-                env_vars.insert(key.to_string(), value.to_string());
-            }
+            symlinks = parse_symlinks(&interpolate(value, &env_vars)?)?;
+        } else if param.strip_prefix("init.env.").is_some() {
+            // Already collected in the first pass above.
         } else if let Some(value) = param.strip_prefix("init.shell=") {
-            // First unwrap backticks, then split on whitespace
+            // First unwrap backticks, then interpolate, then tokenize (quote/escape-aware)
             let shell_cmd = parse_backtick_command(value)?;
-            shell = Some(parse_shell_command(&shell_cmd)?);
+            shell = Some(parse_shell_command(&interpolate(&shell_cmd, &env_vars)?)?);
         } else if let Some(value) = param.strip_prefix("init.script=") {
             let script_cmd = parse_backtick_command(value)?;
-            script = Some(script_cmd);
+            // An empty backtick-wrapped script (`init.script=``) means "no script", same as
+            // omitting init.script entirely, rather than a command with an empty program name.
+            if !script_cmd.trim().is_empty() {
+                script = Some(parse_script(&interpolate(&script_cmd, &env_vars)?)?);
+            }
+        } else if let Some(value) = param.strip_prefix("init.script.file=") {
+            script_file = Some(value.to_string());
+        } else if let Some(value) = param.strip_prefix("init.envfile=") {
+            envfiles = value.split(',').filter(|s| !s.is_empty()).map(String::from).collect();
         } else if let Some(value) = param.strip_prefix("init.moddir=") {
-            moddir = Some(value.to_string());
+            moddir = Some(interpolate(value, &env_vars)?);
         } else if let Some(value) = param.strip_prefix("init.console=") {
             console = Some(value.to_string());
         } else if let Some(value) = param.strip_prefix("init.chdir=") {
-            chdir = Some(value.to_string());
+            chdir = Some(interpolate(value, &env_vars)?);
+        } else if let Some(value) = param.strip_prefix("init.user=") {
+            user = Some(value.to_string());
+        } else if let Some(value) = param.strip_prefix("init.uid=") {
+            uid = Some(value.parse::<u32>().context("init.uid must be a number")?);
+        } else if let Some(value) = param.strip_prefix("init.gid=") {
+            gid = Some(value.parse::<u32>().context("init.gid must be a number")?);
+        } else if let Some(value) = param.strip_prefix("init.groups=") {
+            groups = Some(parse_groups(value)?);
+        } else if let Some(value) = param.strip_prefix("init.home=") {
+            home = Some(value.to_string());
+        } else if let Some(rest) = param.strip_prefix("init.modparam.") {
+            if let Some((name, value)) = rest.split_once('=') {
+                let params = parse_backtick_command(value)?;
+                module_params.insert(name.to_string(), params);
+            }
+        } else if let Some(rest) = param.strip_prefix("init.alias.") {
+            if let Some((name, value)) = rest.split_once('=') {
+                let alias_cmd = parse_backtick_command(value)?;
+                aliases.insert(name.to_string(), parse_shell_command(&alias_cmd)?);
+            }
+        } else if let Some(value) = param.strip_prefix("init.root=") {
+            root_device = Some(value.to_string());
+        } else if let Some(value) = param.strip_prefix("init.rootfstype=") {
+            root_fstype = Some(value.to_string());
+        } else if let Some(value) = param.strip_prefix("init.rootflags=") {
+            root_flags = Some(value.to_string());
+        } else if let Some(value) = param.strip_prefix("init.realinit=") {
+            realinit = Some(value.to_string());
+        } else if let Some(value) = param.strip_prefix("init.control=") {
+            control = Some(value.to_string());
         }
     }
 
     // Ensure required fields are present
     let shell = shell.context("init.shell is required")?;
     let console = console.context("init.console is required")?;
+    let user = build_user_spec(user, uid, gid, groups, home);
+    let switch_root = root_device.map(|device| SwitchRoot {
+        device,
+        fstype: root_fstype,
+        flags: root_flags.unwrap_or_default(),
+        realinit: realinit.unwrap_or_else(|| "/sbin/init".to_string()),
+    });
 
     Ok(Config {
         virtiofs_mounts,
+        ninep_mounts,
         symlinks,
         env_vars,
+        envfiles,
         shell,
         script,
+        script_file,
         moddir,
         console,
         chdir,
+        user,
+        module_params,
+        switch_root,
+        aliases,
+        control,
+    })
+}
+
+/// Parse a comma-separated list of supplementary group ids
+fn parse_groups(value: &str) -> Result<Vec<u32>> {
+    value
+        .split(',')
+        .filter(|s| !s.is_empty())
+        .map(|s| s.parse::<u32>().context("init.groups entries must be numbers"))
+        .collect()
+}
+
+/// Build a `UserSpec` from the individual `init.user`/`init.uid`/`init.gid`/`init.groups`/
+/// `init.home` cmdline keys, falling back to root when no user was configured
+fn build_user_spec(
+    user: Option<String>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    groups: Option<Vec<u32>>,
+    home: Option<String>,
+) -> Option<UserSpec> {
+    // No user-related key present at all: keep today's "run as root" behavior
+    if uid.is_none() && gid.is_none() && groups.as_ref().is_none_or(|g| g.is_empty()) && user.is_none() {
+        return None;
+    }
+
+    let uid = uid.unwrap_or(0);
+    let gid = gid.unwrap_or(uid);
+    let home = home.unwrap_or_else(|| {
+        if uid == 0 {
+            "/root".to_string()
+        } else {
+            format!("/home/{}", uid)
+        }
+    });
+
+    Some(UserSpec {
+        user,
+        uid,
+        gid,
+        groups: groups.unwrap_or_default(),
+        home,
     })
 }
 
@@ -162,6 +761,25 @@ fn parse_cmdline_params(cmdline: &str) -> Vec<String> {
     params
 }
 
+/// Parse a `+`-joined list of lower layers, e.g. "base+squashfs=/img/addon.squashfs".
+/// A bare token is a virtiofs tag; a `squashfs=<path>` token is a squashfs image to loop-mount.
+fn parse_lower_layers(value: &str) -> Result<Vec<LowerLayer>> {
+    let layers: Vec<LowerLayer> = value
+        .split('+')
+        .filter(|s| !s.is_empty())
+        .map(|layer| match layer.strip_prefix("squashfs=") {
+            Some(image) => LowerLayer::Squashfs(image.to_string()),
+            None => LowerLayer::Virtiofs(layer.to_string()),
+        })
+        .collect();
+
+    if layers.is_empty() {
+        anyhow::bail!("Invalid virtiofs layer spec: {}", value);
+    }
+
+    Ok(layers)
+}
+
 fn parse_virtiofs_mounts(value: &str) -> Result<Vec<VirtiofsMount>> {
     let mut mounts = Vec::new();
 
@@ -172,14 +790,14 @@ fn parse_virtiofs_mounts(value: &str) -> Result<Vec<VirtiofsMount>> {
 
         let parts: Vec<&str> = mount_spec.split(':').collect();
 
-        let (tag, path, with_overlay) = match parts.as_slice() {
-            [tag, path] => (*tag, *path, false),
-            [tag, path, overlay] => (*tag, *path, *overlay == "Y"),
+        let (layers, path, with_overlay) = match parts.as_slice() {
+            [layers, path] => (*layers, *path, false),
+            [layers, path, overlay] => (*layers, *path, *overlay == "Y"),
             _ => anyhow::bail!("Invalid virtiofs mount spec: {}", mount_spec),
         };
 
         mounts.push(VirtiofsMount {
-            tag: tag.to_string(),
+            layers: parse_lower_layers(layers)?,
             path: path.to_string(),
             with_overlay,
         });
@@ -188,6 +806,59 @@ fn parse_virtiofs_mounts(value: &str) -> Result<Vec<VirtiofsMount>> {
     Ok(mounts)
 }
 
+/// Parse `init.9p`: semicolon-separated mount specs (so each mount's comma-separated options
+/// don't collide with the separator between mounts), each `tag:path[:opts[:Y]]` where `opts`
+/// is a comma-separated list of `trans=`/`version=`/`msize=`/`cache=`. A third field is always
+/// read as `opts`, so an overlay without options is written `tag:path::Y`.
+fn parse_9p_mounts(value: &str) -> Result<Vec<NinePMount>> {
+    let mut mounts = Vec::new();
+
+    for mount_spec in value.split(';') {
+        if mount_spec.is_empty() {
+            continue;
+        }
+
+        let parts: Vec<&str> = mount_spec.split(':').collect();
+
+        let (tag, path, opts, with_overlay) = match parts.as_slice() {
+            [tag, path] => (*tag, *path, "", false),
+            [tag, path, opts] => (*tag, *path, *opts, false),
+            [tag, path, opts, overlay] => (*tag, *path, *opts, *overlay == "Y"),
+            _ => anyhow::bail!("Invalid 9p mount spec: {}", mount_spec),
+        };
+
+        let mut mount = NinePMount {
+            tag: tag.to_string(),
+            path: path.to_string(),
+            trans: None,
+            version: None,
+            msize: None,
+            cache: None,
+            with_overlay,
+        };
+
+        for opt in opts.split(',').filter(|o| !o.is_empty()) {
+            let (key, value) = opt
+                .split_once('=')
+                .with_context(|| format!("Invalid 9p mount option: {}", opt))?;
+            match key {
+                "trans" => mount.trans = Some(value.to_string()),
+                "version" => mount.version = Some(value.to_string()),
+                "msize" => {
+                    mount.msize =
+                        Some(value.parse::<u32>().context("9p msize must be a number")?)
+                }
+                "cache" => mount.cache = Some(value.to_string()),
+                _ => anyhow::bail!("Unknown 9p mount option: {}", key),
+            }
+        }
+
+        mounts.push(mount);
+    }
+
+    Ok(mounts)
+}
+
 fn parse_symlinks(value: &str) -> Result<Vec<Symlink>> {
     let mut symlinks = Vec::new();
 
@@ -209,6 +880,101 @@ fn parse_symlinks(value: &str) -> Result<Vec<Symlink>> {
     Ok(symlinks)
 }
 
+/// Parse a dotenv-style `init.envfile` file's contents into `KEY=VALUE` pairs, in file order.
+/// Blank lines and `#` comments are ignored; an `export ` prefix on a line is stripped; a value
+/// wrapped in matching single or double quotes has them stripped.
+pub(crate) fn parse_envfile(contents: &str) -> Result<Vec<(String, String)>> {
+    let mut vars = Vec::new();
+
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let line = line.strip_prefix("export ").map_or(line, str::trim_start);
+        let (key, value) = line
+            .split_once('=')
+            .with_context(|| format!("Invalid envfile line: {}", line))?;
+
+        let value = value.trim();
+        let value = match (value.chars().next(), value.chars().last()) {
+            (Some('"'), Some('"')) | (Some('\''), Some('\'')) if value.len() >= 2 => {
+                &value[1..value.len() - 1]
+            }
+            _ => value,
+        };
+
+        vars.push((key.trim().to_string(), value.to_string()));
+    }
+
+    Ok(vars)
+}
+
+/// Expand `${VAR}` / `$VAR` references in `value` against `env`. `${VAR:-default}` falls back to
+/// `default` when `VAR` is unset; `$$` is a literal `$`; an unterminated `${` is an error. Used to
+/// resolve `init.env.*`-sourced variables (collected in [`parse_cmdline`]'s first pass) into the
+/// path-like and command values parsed in its second pass.
+pub(crate) fn interpolate(value: &str, env: &HashMap<String, String>) -> Result<String> {
+    let mut result = String::with_capacity(value.len());
+    let mut chars = value.chars().peekable();
+
+    while let Some(ch) = chars.next() {
+        if ch != '$' {
+            result.push(ch);
+            continue;
+        }
+
+        match chars.peek() {
+            Some('$') => {
+                chars.next();
+                result.push('$');
+            }
+            Some('{') => {
+                chars.next();
+                let mut spec = String::new();
+                let mut closed = false;
+                for c in chars.by_ref() {
+                    if c == '}' {
+                        closed = true;
+                        break;
+                    }
+                    spec.push(c);
+                }
+                if !closed {
+                    anyhow::bail!("Unterminated ${{ in value: {}", value);
+                }
+
+                let (name, default) = match spec.split_once(":-") {
+                    Some((name, default)) => (name, Some(default)),
+                    None => (spec.as_str(), None),
+                };
+                match env.get(name) {
+                    Some(v) => result.push_str(v),
+                    None => result.push_str(default.unwrap_or("")),
+                }
+            }
+            Some(c) if c.is_ascii_alphabetic() || *c == '_' => {
+                let mut name = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_ascii_alphanumeric() || c == '_' {
+                        name.push(c);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                if let Some(v) = env.get(&name) {
+                    result.push_str(v);
+                }
+            }
+            _ => result.push('$'),
+        }
+    }
+
+    Ok(result)
+}
+
 /// Read kernel cmdline from /proc/cmdline
 pub fn read_cmdline() -> Result<String> {
     std::fs::read_to_string("/proc/cmdline")
@@ -220,6 +986,24 @@ pub fn read_cmdline() -> Result<String> {
 mod tests {
     use super::*;
 
+    /// Build the single-pipeline, single-command `Script` a plain `program arg1 arg2` value
+    /// parses to, so script assertions don't have to spell out the full AST each time.
+    fn script_single(program: &str, args: &[&str]) -> Script {
+        Script {
+            pipelines: vec![(
+                PipelineJoin::Sequential,
+                Pipeline {
+                    commands: vec![Command {
+                        program: program.to_string(),
+                        args: args.iter().map(|s| s.to_string()).collect(),
+                        stdin: None,
+                        stdout: None,
+                    }],
+                },
+            )],
+        }
+    }
+
     #[test]
     fn test_parse_empty_cmdline() {
         let result = parse_cmdline("");
@@ -236,7 +1020,10 @@ mod tests {
             parse_cmdline("init.console=console init.shell=`sh` init.virtiofs=share:/mnt/share")
                 .unwrap();
         assert_eq!(config.virtiofs_mounts.len(), 1);
-        assert_eq!(config.virtiofs_mounts[0].tag, "share");
+        assert_eq!(
+            config.virtiofs_mounts[0].layers,
+            vec![LowerLayer::Virtiofs("share".to_string())]
+        );
         assert_eq!(config.virtiofs_mounts[0].path, "/mnt/share");
         assert!(!config.virtiofs_mounts[0].with_overlay);
     }
@@ -257,10 +1044,16 @@ mod tests {
         )
         .unwrap();
         assert_eq!(config.virtiofs_mounts.len(), 2);
-        assert_eq!(config.virtiofs_mounts[0].tag, "share1");
+        assert_eq!(
+            config.virtiofs_mounts[0].layers,
+            vec![LowerLayer::Virtiofs("share1".to_string())]
+        );
         assert_eq!(config.virtiofs_mounts[0].path, "/mnt/a");
         assert!(!config.virtiofs_mounts[0].with_overlay);
-        assert_eq!(config.virtiofs_mounts[1].tag, "share2");
+        assert_eq!(
+            config.virtiofs_mounts[1].layers,
+            vec![LowerLayer::Virtiofs("share2".to_string())]
+        );
         assert_eq!(config.virtiofs_mounts[1].path, "/mnt/b");
         assert!(config.virtiofs_mounts[1].with_overlay);
     }
@@ -314,7 +1107,7 @@ mod tests {
         let config =
             parse_cmdline("init.console=console init.shell=`sh` init.script=`/bin/echo hello`")
                 .unwrap();
-        assert_eq!(config.script, Some("/bin/echo hello".to_string()));
+        assert_eq!(config.script, Some(script_single("/bin/echo", &["hello"])));
         assert_eq!(config.console, "console");
     }
 
@@ -324,7 +1117,10 @@ mod tests {
         let config = parse_cmdline(cmdline).unwrap();
 
         assert_eq!(config.virtiofs_mounts.len(), 1);
-        assert_eq!(config.virtiofs_mounts[0].tag, "share");
+        assert_eq!(
+            config.virtiofs_mounts[0].layers,
+            vec![LowerLayer::Virtiofs("share".to_string())]
+        );
         assert_eq!(config.virtiofs_mounts[0].path, "/mnt");
         assert!(config.virtiofs_mounts[0].with_overlay);
 
@@ -343,6 +1139,35 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_parse_virtiofs_stacked_layers() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.virtiofs=base+squashfs=/img/addon.squashfs:/mnt/root",
+        )
+        .unwrap();
+        assert_eq!(
+            config.virtiofs_mounts[0].layers,
+            vec![
+                LowerLayer::Virtiofs("base".to_string()),
+                LowerLayer::Squashfs("/img/addon.squashfs".to_string()),
+            ]
+        );
+        assert_eq!(config.virtiofs_mounts[0].path, "/mnt/root");
+    }
+
+    #[test]
+    fn test_parse_virtiofs_squashfs_only() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.virtiofs=squashfs=/img/base.squashfs:/mnt/root:Y",
+        )
+        .unwrap();
+        assert_eq!(
+            config.virtiofs_mounts[0].layers,
+            vec![LowerLayer::Squashfs("/img/base.squashfs".to_string())]
+        );
+        assert!(config.virtiofs_mounts[0].with_overlay);
+    }
+
     #[test]
     fn test_parse_invalid_symlink() {
         let result = parse_cmdline("init.symlinks=invalid");
@@ -355,7 +1180,10 @@ mod tests {
             "init.console=console init.shell=`sh` init.script=`/bin/echo hello world`",
         )
         .unwrap();
-        assert_eq!(config.script, Some("/bin/echo hello world".to_string()));
+        assert_eq!(
+            config.script,
+            Some(script_single("/bin/echo", &["hello", "world"]))
+        );
     }
 
     #[test]
@@ -364,7 +1192,10 @@ mod tests {
             "init.console=console init.shell=`sh` init.script=`/usr/bin/ls -la /tmp`",
         )
         .unwrap();
-        assert_eq!(config.script, Some("/usr/bin/ls -la /tmp".to_string()));
+        assert_eq!(
+            config.script,
+            Some(script_single("/usr/bin/ls", &["-la", "/tmp"]))
+        );
     }
 
     #[test]
@@ -382,7 +1213,10 @@ mod tests {
         let cmdline =
             "console=ttyS0 init.console=ttyS0 init.shell=`sh` init.env.PATH=/usr/bin init.script=`/bin/echo hello world` quiet";
         let config = parse_cmdline(cmdline).unwrap();
-        assert_eq!(config.script, Some("/bin/echo hello world".to_string()));
+        assert_eq!(
+            config.script,
+            Some(script_single("/bin/echo", &["hello", "world"]))
+        );
         assert_eq!(config.env_vars.get("PATH"), Some(&"/usr/bin".to_string()));
     }
 
@@ -394,7 +1228,7 @@ mod tests {
         .unwrap();
         assert_eq!(
             config.script,
-            Some("/bin/echo   multiple   spaces".to_string())
+            Some(script_single("/bin/echo", &["multiple", "spaces"]))
         );
     }
 
@@ -404,24 +1238,42 @@ mod tests {
             "init.console=console init.shell=`sh` init.script=`/bin/sh -c \"echo test\"`",
         )
         .unwrap();
-        assert_eq!(config.script, Some("/bin/sh -c \"echo test\"".to_string()));
+        assert_eq!(
+            config.script,
+            Some(script_single("/bin/sh", &["-c", "echo test"]))
+        );
     }
 
     #[test]
     fn test_parse_empty_backticked_command() {
         let config = parse_cmdline("init.console=console init.shell=`sh` init.script=``").unwrap();
-        assert_eq!(config.script, Some("".to_string()));
+        assert_eq!(config.script, None);
     }
 
     #[test]
-    fn test_parse_cmdline_with_all_features_and_backticks() {
-        let cmdline = "console=ttyS0 init.console=ttyS0 init.virtiofs=share:/mnt:Y init.symlinks=/bin/sh:/bin/bash init.env.PATH=/usr/bin init.env.HOME=/root init.shell=`sh` init.script=`/bin/echo test 1 2 3` init.moddir=/lib/modules quiet";
-        let config = parse_cmdline(cmdline).unwrap();
-
-        assert_eq!(config.virtiofs_mounts.len(), 1);
-        assert_eq!(config.virtiofs_mounts[0].tag, "share");
-        assert_eq!(config.virtiofs_mounts[0].path, "/mnt");
-        assert!(config.virtiofs_mounts[0].with_overlay);
+    fn test_parse_shell_with_quoted_argument() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh -c \"echo hi\"`",
+        )
+        .unwrap();
+        assert_eq!(
+            config.shell,
+            ("sh".to_string(), vec!["-c".to_string(), "echo hi".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_parse_cmdline_with_all_features_and_backticks() {
+        let cmdline = "console=ttyS0 init.console=ttyS0 init.virtiofs=share:/mnt:Y init.symlinks=/bin/sh:/bin/bash init.env.PATH=/usr/bin init.env.HOME=/root init.shell=`sh` init.script=`/bin/echo test 1 2 3` init.moddir=/lib/modules quiet";
+        let config = parse_cmdline(cmdline).unwrap();
+
+        assert_eq!(config.virtiofs_mounts.len(), 1);
+        assert_eq!(
+            config.virtiofs_mounts[0].layers,
+            vec![LowerLayer::Virtiofs("share".to_string())]
+        );
+        assert_eq!(config.virtiofs_mounts[0].path, "/mnt");
+        assert!(config.virtiofs_mounts[0].with_overlay);
 
         assert_eq!(config.symlinks.len(), 1);
         assert_eq!(config.symlinks[0].source, "/bin/sh");
@@ -432,7 +1284,10 @@ mod tests {
         assert_eq!(config.env_vars.get("HOME"), Some(&"/root".to_string()));
 
         assert_eq!(config.shell, ("sh".to_string(), vec![]));
-        assert_eq!(config.script, Some("/bin/echo test 1 2 3".to_string()));
+        assert_eq!(
+            config.script,
+            Some(script_single("/bin/echo", &["test", "1", "2", "3"]))
+        );
 
         assert_eq!(config.moddir, Some("/lib/modules".to_string()));
         assert_eq!(config.console, "ttyS0");
@@ -471,7 +1326,10 @@ mod tests {
             "init.console=console init.shell=`sh` init.script=`/bin/env KEY=VALUE ls`",
         )
         .unwrap();
-        assert_eq!(config.script, Some("/bin/env KEY=VALUE ls".to_string()));
+        assert_eq!(
+            config.script,
+            Some(script_single("/bin/env", &["KEY=VALUE", "ls"]))
+        );
     }
 
     #[test]
@@ -480,7 +1338,10 @@ mod tests {
             "init.console=console init.shell=`sh` init.script=`FOO=bar BAZ=qux /bin/test`",
         )
         .unwrap();
-        assert_eq!(config.script, Some("FOO=bar BAZ=qux /bin/test".to_string()));
+        assert_eq!(
+            config.script,
+            Some(script_single("FOO=bar", &["BAZ=qux", "/bin/test"]))
+        );
     }
 
     #[test]
@@ -488,16 +1349,518 @@ mod tests {
         let cmdline =
             "console=ttyS0 init.console=ttyS0 init.shell=`sh` init.env.PATH=/usr/bin init.shell=`sh` init.script=`/bin/env TEST=123 ls -la` quiet";
         let config = parse_cmdline(cmdline).unwrap();
-        assert_eq!(config.script, Some("/bin/env TEST=123 ls -la".to_string()));
+        assert_eq!(
+            config.script,
+            Some(script_single("/bin/env", &["TEST=123", "ls", "-la"]))
+        );
         assert_eq!(config.env_vars.get("PATH"), Some(&"/usr/bin".to_string()));
     }
 
+    #[test]
+    fn test_parse_no_user_defaults_to_none() {
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert_eq!(config.user, None);
+    }
+
+    #[test]
+    fn test_parse_uid_gid() {
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.uid=1000 init.gid=100")
+                .unwrap();
+        let user = config.user.unwrap();
+        assert_eq!(user.uid, 1000);
+        assert_eq!(user.gid, 100);
+        assert_eq!(user.groups, Vec::<u32>::new());
+        assert_eq!(user.home, "/home/1000");
+    }
+
+    #[test]
+    fn test_parse_uid_without_gid_defaults_gid_to_uid() {
+        let config = parse_cmdline("init.console=console init.shell=`sh` init.uid=1000").unwrap();
+        let user = config.user.unwrap();
+        assert_eq!(user.gid, 1000);
+    }
+
+    #[test]
+    fn test_parse_user_full() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.user=dev init.uid=1000 init.gid=1000 init.groups=27,100 init.home=/home/dev",
+        )
+        .unwrap();
+        let user = config.user.unwrap();
+        assert_eq!(user.user, Some("dev".to_string()));
+        assert_eq!(user.uid, 1000);
+        assert_eq!(user.gid, 1000);
+        assert_eq!(user.groups, vec![27, 100]);
+        assert_eq!(user.home, "/home/dev");
+    }
+
+    #[test]
+    fn test_parse_invalid_uid() {
+        let result = parse_cmdline("init.console=console init.shell=`sh` init.uid=notanumber");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_invalid_groups() {
+        let result = parse_cmdline("init.console=console init.shell=`sh` init.groups=27,abc");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_modparam() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.modparam.e1000e=`debug=1 InterruptThrottleRate=0`",
+        )
+        .unwrap();
+        assert_eq!(
+            config.module_params.get("e1000e"),
+            Some(&"debug=1 InterruptThrottleRate=0".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_no_switch_root_by_default() {
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert_eq!(config.switch_root, None);
+    }
+
+    #[test]
+    fn test_parse_switch_root_minimal() {
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.root=/dev/vda1").unwrap();
+        let sr = config.switch_root.unwrap();
+        assert_eq!(sr.device, "/dev/vda1");
+        assert_eq!(sr.fstype, None);
+        assert_eq!(sr.flags, "");
+        assert_eq!(sr.realinit, "/sbin/init");
+    }
+
+    #[test]
+    fn test_parse_switch_root_full() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.root=/dev/vda1 init.rootfstype=ext4 init.rootflags=ro,noatime init.realinit=/usr/lib/systemd/systemd",
+        )
+        .unwrap();
+        let sr = config.switch_root.unwrap();
+        assert_eq!(sr.device, "/dev/vda1");
+        assert_eq!(sr.fstype, Some("ext4".to_string()));
+        assert_eq!(sr.flags, "ro,noatime");
+        assert_eq!(sr.realinit, "/usr/lib/systemd/systemd");
+    }
+
+    #[test]
+    fn test_parse_9p_basic() {
+        let config = parse_cmdline("init.console=console init.shell=`sh` init.9p=home:/mnt/home")
+            .unwrap();
+        assert_eq!(config.ninep_mounts.len(), 1);
+        assert_eq!(config.ninep_mounts[0].tag, "home");
+        assert_eq!(config.ninep_mounts[0].path, "/mnt/home");
+        assert_eq!(config.ninep_mounts[0].trans, None);
+        assert!(!config.ninep_mounts[0].with_overlay);
+    }
+
+    #[test]
+    fn test_parse_9p_with_options_and_overlay() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.9p=home:/mnt/home:trans=virtio,version=9p2000.L,msize=262144,cache=loose:Y",
+        )
+        .unwrap();
+        let m = &config.ninep_mounts[0];
+        assert_eq!(m.trans, Some("virtio".to_string()));
+        assert_eq!(m.version, Some("9p2000.L".to_string()));
+        assert_eq!(m.msize, Some(262144));
+        assert_eq!(m.cache, Some("loose".to_string()));
+        assert!(m.with_overlay);
+    }
+
+    #[test]
+    fn test_parse_9p_multiple() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.9p=a:/mnt/a;b:/mnt/b:trans=virtio",
+        )
+        .unwrap();
+        assert_eq!(config.ninep_mounts.len(), 2);
+        assert_eq!(config.ninep_mounts[1].tag, "b");
+        assert_eq!(config.ninep_mounts[1].trans, Some("virtio".to_string()));
+    }
+
+    #[test]
+    fn test_parse_9p_invalid_option() {
+        let result = parse_cmdline(
+            "init.console=console init.shell=`sh` init.9p=home:/mnt/home:bogus=1",
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_script_file() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.script.file=/etc/kdf-init.steps",
+        )
+        .unwrap();
+        assert_eq!(
+            config.script_file,
+            Some("/etc/kdf-init.steps".to_string())
+        );
+    }
+
     #[test]
     fn test_parse_shell_and_script_together() {
         let config =
             parse_cmdline("init.console=console init.shell=`/bin/sh` init.script=`/bin/ls`")
                 .unwrap();
         assert_eq!(config.shell, ("/bin/sh".to_string(), vec![]));
-        assert_eq!(config.script, Some("/bin/ls".to_string()));
+        assert_eq!(config.script, Some(script_single("/bin/ls", &[])));
+    }
+
+    #[test]
+    fn test_parse_script_pipeline() {
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.script=`mount x | grep y`")
+                .unwrap();
+        let script = config.script.unwrap();
+        assert_eq!(script.pipelines.len(), 1);
+        let (join, pipeline) = &script.pipelines[0];
+        assert_eq!(*join, PipelineJoin::Sequential);
+        assert_eq!(
+            pipeline.commands,
+            vec![
+                Command { program: "mount".to_string(), args: vec!["x".to_string()], stdin: None, stdout: None },
+                Command { program: "grep".to_string(), args: vec!["y".to_string()], stdin: None, stdout: None },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_script_sequential_and_conditional() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.script=`mount x | grep y && touch /ready`",
+        )
+        .unwrap();
+        let script = config.script.unwrap();
+        assert_eq!(script.pipelines.len(), 2);
+        assert_eq!(script.pipelines[0].0, PipelineJoin::Sequential);
+        assert_eq!(script.pipelines[0].1.commands.len(), 2);
+        assert_eq!(script.pipelines[1].0, PipelineJoin::And);
+        assert_eq!(
+            script.pipelines[1].1.commands,
+            vec![Command {
+                program: "touch".to_string(),
+                args: vec!["/ready".to_string()],
+                stdin: None,
+                stdout: None
+            }]
+        );
+    }
+
+    #[test]
+    fn test_parse_script_semicolon_and_or() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.script=`true ; false || echo fallback`",
+        )
+        .unwrap();
+        let script = config.script.unwrap();
+        assert_eq!(script.pipelines.len(), 3);
+        assert_eq!(script.pipelines[0].0, PipelineJoin::Sequential);
+        assert_eq!(script.pipelines[1].0, PipelineJoin::Sequential);
+        assert_eq!(script.pipelines[2].0, PipelineJoin::Or);
+    }
+
+    #[test]
+    fn test_parse_script_redirections() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.script=`sort <in.txt >out.txt`",
+        )
+        .unwrap();
+        let script = config.script.unwrap();
+        let command = &script.pipelines[0].1.commands[0];
+        assert_eq!(command.stdin, Some("in.txt".to_string()));
+        assert_eq!(command.stdout, Some(Redirect::Truncate("out.txt".to_string())));
+    }
+
+    #[test]
+    fn test_parse_script_append_redirection() {
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.script=`echo hi >>log.txt`")
+                .unwrap();
+        let script = config.script.unwrap();
+        let command = &script.pipelines[0].1.commands[0];
+        assert_eq!(command.stdout, Some(Redirect::Append("log.txt".to_string())));
+    }
+
+    #[test]
+    fn test_parse_script_empty_pipeline_errors() {
+        let result =
+            parse_cmdline("init.console=console init.shell=`sh` init.script=`echo a ;; echo b`");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_script_bare_ampersand_errors() {
+        let result =
+            parse_cmdline("init.console=console init.shell=`sh` init.script=`echo a & echo b`");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_script_dangling_redirect_errors() {
+        let result = parse_cmdline("init.console=console init.shell=`sh` init.script=`sort >`");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_script_mid_pipeline_stdout_redirect_errors() {
+        let result = parse_cmdline(
+            "init.console=console init.shell=`sh` init.script=`a | b >file | c`",
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_script_mid_pipeline_stdin_redirect_errors() {
+        let result = parse_cmdline(
+            "init.console=console init.shell=`sh` init.script=`a | b <file | c`",
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_envfile_basic() {
+        let vars = parse_envfile("PATH=/usr/bin\nHOME=/root\n").unwrap();
+        assert_eq!(
+            vars,
+            vec![
+                ("PATH".to_string(), "/usr/bin".to_string()),
+                ("HOME".to_string(), "/root".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_envfile_comments_and_blank_lines() {
+        let vars = parse_envfile("# a comment\n\nFOO=bar\n  # indented comment\n").unwrap();
+        assert_eq!(vars, vec![("FOO".to_string(), "bar".to_string())]);
+    }
+
+    #[test]
+    fn test_parse_envfile_export_prefix() {
+        let vars = parse_envfile("export FOO=bar\n").unwrap();
+        assert_eq!(vars, vec![("FOO".to_string(), "bar".to_string())]);
+    }
+
+    #[test]
+    fn test_parse_envfile_quoted_values() {
+        let vars = parse_envfile("A=\"hello world\"\nB='single quoted'\n").unwrap();
+        assert_eq!(
+            vars,
+            vec![
+                ("A".to_string(), "hello world".to_string()),
+                ("B".to_string(), "single quoted".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_envfile_invalid_line() {
+        let result = parse_envfile("not_a_valid_line\n");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_cmdline_envfile() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.envfile=/etc/a.env,/etc/b.env",
+        )
+        .unwrap();
+        assert_eq!(
+            config.envfiles,
+            vec!["/etc/a.env".to_string(), "/etc/b.env".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_parse_cmdline_no_envfile_by_default() {
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert_eq!(config.envfiles, Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_parse_alias() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.alias.ready=`touch /run/ready`",
+        )
+        .unwrap();
+        assert_eq!(
+            config.aliases.get("ready"),
+            Some(&("touch".to_string(), vec!["/run/ready".to_string()]))
+        );
+    }
+
+    #[test]
+    fn test_expand_alias_basic() {
+        let mut aliases = HashMap::new();
+        aliases.insert("ready".to_string(), ("touch".to_string(), vec!["/run/ready".to_string()]));
+        let expanded =
+            expand_alias(("ready".to_string(), vec!["--verbose".to_string()]), &aliases).unwrap();
+        assert_eq!(
+            expanded,
+            ("touch".to_string(), vec!["/run/ready".to_string(), "--verbose".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_expand_alias_no_match_is_unchanged() {
+        let aliases = HashMap::new();
+        let expanded =
+            expand_alias(("sh".to_string(), vec!["-i".to_string()]), &aliases).unwrap();
+        assert_eq!(expanded, ("sh".to_string(), vec!["-i".to_string()]));
+    }
+
+    #[test]
+    fn test_expand_alias_chain() {
+        let mut aliases = HashMap::new();
+        aliases.insert("a".to_string(), ("b".to_string(), vec![]));
+        aliases.insert("b".to_string(), ("c".to_string(), vec!["-x".to_string()]));
+        let expanded = expand_alias(("a".to_string(), vec!["y".to_string()]), &aliases).unwrap();
+        assert_eq!(expanded, ("c".to_string(), vec!["-x".to_string(), "y".to_string()]));
+    }
+
+    #[test]
+    fn test_expand_alias_loop_errors() {
+        let mut aliases = HashMap::new();
+        aliases.insert("a".to_string(), ("b".to_string(), vec![]));
+        aliases.insert("b".to_string(), ("a".to_string(), vec![]));
+        let result = expand_alias(("a".to_string(), vec![]), &aliases);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_expand_script_aliases() {
+        let mut aliases = HashMap::new();
+        aliases.insert("ready".to_string(), ("touch".to_string(), vec!["/run/ready".to_string()]));
+        let mut script = script_single("ready", &[]);
+        expand_script_aliases(&mut script, &aliases).unwrap();
+        assert_eq!(
+            script.pipelines[0].1.commands[0],
+            Command {
+                program: "touch".to_string(),
+                args: vec!["/run/ready".to_string()],
+                stdin: None,
+                stdout: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_interpolate_braced() {
+        let mut env = HashMap::new();
+        env.insert("ROOT".to_string(), "/mnt/data".to_string());
+        assert_eq!(interpolate("${ROOT}/share", &env).unwrap(), "/mnt/data/share");
+    }
+
+    #[test]
+    fn test_interpolate_bare() {
+        let mut env = HashMap::new();
+        env.insert("ROOT".to_string(), "/mnt/data".to_string());
+        assert_eq!(interpolate("$ROOT/share", &env).unwrap(), "/mnt/data/share");
+    }
+
+    #[test]
+    fn test_interpolate_unset_bare_is_empty() {
+        let env = HashMap::new();
+        assert_eq!(interpolate("$MISSING/share", &env).unwrap(), "/share");
+    }
+
+    #[test]
+    fn test_interpolate_default_fallback() {
+        let env = HashMap::new();
+        assert_eq!(
+            interpolate("${ROOT:-/mnt/default}/share", &env).unwrap(),
+            "/mnt/default/share"
+        );
+    }
+
+    #[test]
+    fn test_interpolate_default_unused_when_set() {
+        let mut env = HashMap::new();
+        env.insert("ROOT".to_string(), "/mnt/data".to_string());
+        assert_eq!(
+            interpolate("${ROOT:-/mnt/default}/share", &env).unwrap(),
+            "/mnt/data/share"
+        );
+    }
+
+    #[test]
+    fn test_interpolate_literal_dollar() {
+        let env = HashMap::new();
+        assert_eq!(interpolate("price: $$5", &env).unwrap(), "price: $5");
+    }
+
+    #[test]
+    fn test_interpolate_unterminated_brace_errors() {
+        let env = HashMap::new();
+        assert!(interpolate("${ROOT", &env).is_err());
+    }
+
+    #[test]
+    fn test_parse_cmdline_interpolates_virtiofs_path() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.env.ROOT=/mnt/data \
+             init.virtiofs=tag0:${ROOT}/share",
+        )
+        .unwrap();
+        assert_eq!(config.virtiofs_mounts[0].path, "/mnt/data/share");
+    }
+
+    #[test]
+    fn test_parse_cmdline_interpolates_chdir_and_moddir() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.env.ROOT=/mnt/data \
+             init.chdir=${ROOT}/work init.moddir=${ROOT}/modules",
+        )
+        .unwrap();
+        assert_eq!(config.chdir, Some("/mnt/data/work".to_string()));
+        assert_eq!(config.moddir, Some("/mnt/data/modules".to_string()));
+    }
+
+    #[test]
+    fn test_parse_cmdline_interpolates_shell() {
+        let config = parse_cmdline(
+            "init.console=console init.env.SHELL_BIN=/bin/bash init.shell=`${SHELL_BIN} -i`",
+        )
+        .unwrap();
+        assert_eq!(config.shell, ("/bin/bash".to_string(), vec!["-i".to_string()]));
+    }
+
+    #[test]
+    fn test_parse_cmdline_interpolates_script_regardless_of_order() {
+        // init.env.* appears after init.script on the cmdline; the two-pass parse must still see it.
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.script=`touch ${MARKER}` init.env.MARKER=/run/ready",
+        )
+        .unwrap();
+        assert_eq!(config.script, Some(script_single("touch", &["/run/ready"])));
+    }
+
+    #[test]
+    fn test_parse_cmdline_interpolate_error_propagates() {
+        let result = parse_cmdline("init.console=console init.shell=`sh` init.chdir=${UNCLOSED");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_control() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.control=/run/kdf-init.sock",
+        )
+        .unwrap();
+        assert_eq!(config.control, Some("/run/kdf-init.sock".to_string()));
+    }
+
+    #[test]
+    fn test_parse_cmdline_no_control_by_default() {
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert_eq!(config.control, None);
     }
 }
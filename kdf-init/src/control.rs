@@ -0,0 +1,293 @@
+//! Control socket for runtime command injection: `init.control=<path>` has kdf-init listen on a
+//! Unix socket for one-shot command requests, modeled on Mercurial's chg command-server
+//! `CommandSpec` (command, current_dir, envs) and its length-prefixed binary frame protocol.
+//!
+//! Frame layout: a 4-byte big-endian total payload length, then three length-prefixed blobs in
+//! order: argv (NUL-separated), current_dir, and envs (NUL-separated `KEY=VALUE` entries). A
+//! connection sends exactly one frame and gets back a single 4-byte big-endian exit code.
+
+use anyhow::{Context, Result};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+
+/// A single decoded command request: argv, optional working directory, and extra environment
+/// variables to merge over kdf-init's own environment before spawning.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandSpec {
+    pub argv: Vec<String>,
+    pub current_dir: Option<PathBuf>,
+    pub envs: Vec<(String, String)>,
+}
+
+/// Cap on a frame's declared payload length, guarding against a runaway allocation from a
+/// corrupt or malicious length prefix.
+const MAX_FRAME_LEN: usize = 1 << 20;
+
+/// Encode `spec` into a full frame (including its leading 4-byte length prefix), ready to write
+/// directly to the control socket. kdf-init itself only ever decodes frames (it's the server
+/// side of this protocol); this exists for [`decode_frame`]'s round-trip tests below.
+#[cfg(test)]
+pub fn encode_frame(spec: &CommandSpec) -> Vec<u8> {
+    let argv_blob = join_nul(&spec.argv);
+    let cwd_blob = spec
+        .current_dir
+        .as_ref()
+        .map(|p| p.to_string_lossy().into_owned().into_bytes())
+        .unwrap_or_default();
+    let env_entries: Vec<String> =
+        spec.envs.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+    let envs_blob = join_nul(&env_entries);
+
+    let mut payload = Vec::new();
+    for blob in [&argv_blob, &cwd_blob, &envs_blob] {
+        payload.extend_from_slice(&(blob.len() as u32).to_be_bytes());
+        payload.extend_from_slice(blob);
+    }
+
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&payload);
+    frame
+}
+
+/// Decode a frame's payload (i.e. everything after the leading 4-byte total length has already
+/// been read off the wire) into a [`CommandSpec`]. Rejects a blob whose declared length would
+/// run past the end of `payload`.
+pub fn decode_frame(payload: &[u8]) -> Result<CommandSpec> {
+    let mut pos = 0;
+    let mut blobs: Vec<&[u8]> = Vec::with_capacity(3);
+
+    for _ in 0..3 {
+        let len_bytes = payload
+            .get(pos..pos + 4)
+            .context("control frame truncated reading a blob length")?;
+        let len = u32::from_be_bytes(len_bytes.try_into().unwrap()) as usize;
+        pos += 4;
+
+        let end = pos.checked_add(len).context("control frame blob length overflows")?;
+        if end > payload.len() {
+            anyhow::bail!("control frame blob length {} exceeds buffer", len);
+        }
+        blobs.push(&payload[pos..end]);
+        pos = end;
+    }
+
+    let argv = split_nul(blobs[0])?;
+    if argv.is_empty() {
+        anyhow::bail!("control frame has empty argv");
+    }
+
+    let current_dir = if blobs[1].is_empty() {
+        None
+    } else {
+        let cwd = String::from_utf8(blobs[1].to_vec())
+            .context("control frame current_dir is not valid UTF-8")?;
+        Some(PathBuf::from(cwd))
+    };
+
+    let envs = split_nul(blobs[2])?
+        .into_iter()
+        .map(|entry| {
+            entry
+                .split_once('=')
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .with_context(|| format!("control frame env entry missing '=': {}", entry))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(CommandSpec { argv, current_dir, envs })
+}
+
+#[cfg(test)]
+fn join_nul(parts: &[String]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for (i, part) in parts.iter().enumerate() {
+        if i > 0 {
+            buf.push(0);
+        }
+        buf.extend_from_slice(part.as_bytes());
+    }
+    buf
+}
+
+fn split_nul(buf: &[u8]) -> Result<Vec<String>> {
+    if buf.is_empty() {
+        return Ok(Vec::new());
+    }
+    buf.split(|&b| b == 0)
+        .map(|s| String::from_utf8(s.to_vec()).context("control frame blob is not valid UTF-8"))
+        .collect()
+}
+
+/// Bind a Unix socket at `path` (removing any stale socket file left over from a previous run)
+/// and spawn a background thread that serves one frame per connection: decode it, spawn the
+/// command with `current_dir`/`envs` merged over kdf-init's own environment, wait for it, and
+/// write the 4-byte exit code back before closing the connection. A bad connection (malformed
+/// frame, failed spawn) is logged and doesn't affect the listener or any other connection.
+pub fn spawn_listener(path: &str) -> Result<()> {
+    let _ = std::fs::remove_file(path);
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("Failed to bind control socket at {}", path))?;
+
+    println!("kdf-init: control socket listening at {}", path);
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = handle_connection(stream) {
+                        eprintln!("kdf-init: control connection error: {:?}", e);
+                    }
+                }
+                Err(e) => eprintln!("kdf-init: control socket accept error: {}", e),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn handle_connection(mut stream: UnixStream) -> Result<()> {
+    use std::io::{Read, Write};
+
+    let mut len_buf = [0u8; 4];
+    stream
+        .read_exact(&mut len_buf)
+        .context("Failed to read control frame length")?;
+    let len = u32::from_be_bytes(len_buf) as usize;
+    if len > MAX_FRAME_LEN {
+        anyhow::bail!("control frame length {} exceeds max {}", len, MAX_FRAME_LEN);
+    }
+
+    let mut payload = vec![0u8; len];
+    stream
+        .read_exact(&mut payload)
+        .context("Failed to read control frame payload")?;
+
+    let spec = decode_frame(&payload)?;
+    println!("kdf-init: control command: {}", spec.argv.join(" "));
+
+    let code = match run_command(&spec) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("kdf-init: control command failed: {:?}", e);
+            -1
+        }
+    };
+
+    stream
+        .write_all(&code.to_be_bytes())
+        .context("Failed to write control response")?;
+
+    Ok(())
+}
+
+/// Spawn `spec`'s argv, with `current_dir`/`envs` merged over kdf-init's own environment, wait
+/// for it to finish, and return its exit code (-1 if it was killed by a signal).
+///
+/// Unlike the interactive shell and `init.script`/`init.script.file` steps (see
+/// [`crate::system::apply_user`]), this always runs as root: the control socket has no
+/// per-request notion of a calling user to drop to, and anything that can reach
+/// `init.control`'s socket already has the access needed to ask kdf-init to run arbitrary
+/// commands as root, so the socket's file permissions are the actual access boundary here.
+fn run_command(spec: &CommandSpec) -> Result<i32> {
+    let (program, args) = spec.argv.split_first().context("control frame has empty argv")?;
+
+    let mut command = std::process::Command::new(program);
+    command.args(args);
+    if let Some(dir) = &spec.current_dir {
+        command.current_dir(dir);
+    }
+    for (key, value) in &spec.envs {
+        command.env(key, value);
+    }
+
+    let status = command
+        .status()
+        .with_context(|| format!("Failed to run control command '{}'", program))?;
+
+    Ok(status.code().unwrap_or(-1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip_full_spec() {
+        let spec = CommandSpec {
+            argv: vec!["echo".to_string(), "hello".to_string()],
+            current_dir: Some(PathBuf::from("/tmp")),
+            envs: vec![("FOO".to_string(), "bar".to_string())],
+        };
+
+        let frame = encode_frame(&spec);
+        let len = u32::from_be_bytes(frame[0..4].try_into().unwrap()) as usize;
+        assert_eq!(len, frame.len() - 4);
+
+        let decoded = decode_frame(&frame[4..]).unwrap();
+        assert_eq!(decoded, spec);
+    }
+
+    #[test]
+    fn test_round_trip_no_cwd_no_envs() {
+        let spec = CommandSpec {
+            argv: vec!["true".to_string()],
+            current_dir: None,
+            envs: vec![],
+        };
+
+        let frame = encode_frame(&spec);
+        let decoded = decode_frame(&frame[4..]).unwrap();
+        assert_eq!(decoded, spec);
+    }
+
+    #[test]
+    fn test_round_trip_multiple_envs() {
+        let spec = CommandSpec {
+            argv: vec!["env".to_string()],
+            current_dir: None,
+            envs: vec![
+                ("A".to_string(), "1".to_string()),
+                ("B".to_string(), "2".to_string()),
+            ],
+        };
+
+        let frame = encode_frame(&spec);
+        let decoded = decode_frame(&frame[4..]).unwrap();
+        assert_eq!(decoded, spec);
+    }
+
+    #[test]
+    fn test_decode_empty_argv_errors() {
+        let spec = CommandSpec { argv: vec![], current_dir: None, envs: vec![] };
+        let frame = encode_frame(&spec);
+        assert!(decode_frame(&frame[4..]).is_err());
+    }
+
+    #[test]
+    fn test_decode_rejects_blob_length_past_buffer_end() {
+        // Claim a 1000-byte argv blob but supply no such bytes.
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&1000u32.to_be_bytes());
+        let result = decode_frame(&payload);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_decode_truncated_header_errors() {
+        let result = decode_frame(&[0, 0]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_decode_env_entry_missing_equals_errors() {
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&4u32.to_be_bytes());
+        payload.extend_from_slice(b"true");
+        payload.extend_from_slice(&0u32.to_be_bytes());
+        payload.extend_from_slice(&6u32.to_be_bytes());
+        payload.extend_from_slice(b"NOTKVP");
+        assert!(decode_frame(&payload).is_err());
+    }
+}
@@ -1,10 +1,14 @@
 //! kdf-init: minimal Rust init for initramfs with virtiofs and overlayfs support
 
 mod cmdline;
+mod control;
+mod ninep;
+mod seq;
+mod switch_root;
 mod system;
 mod virtiofs;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 
 fn main() -> Result<()> {
     // Run main logic and always shutdown, even on error
@@ -26,23 +30,56 @@ fn run() -> Result<()> {
     let cmdline_str = cmdline::read_cmdline()?;
     println!("kdf-init: kernel cmdline: {}", cmdline_str);
 
-    let config = cmdline::parse_cmdline(&cmdline_str)?;
+    let mut config = cmdline::parse_cmdline(&cmdline_str)?;
 
     println!("kdf-init: parsed configuration:");
     println!("  virtiofs mounts: {}", config.virtiofs_mounts.len());
+    println!("  9p mounts: {}", config.ninep_mounts.len());
     println!("  symlinks: {}", config.symlinks.len());
     println!("  env vars: {}", config.env_vars.len());
     println!("  shell: {:?}", config.shell);
     println!("  script: {:?}", config.script);
 
     // Load kernel modules from configured directory
-    system::load_kernel_modules(config.moddir.as_deref())?;
+    system::load_kernel_modules(config.moddir.as_deref(), &config.module_params)?;
 
     // Mount virtiofs shares with optional overlayfs
     virtiofs::mount_virtiofs_shares(&config.virtiofs_mounts)?;
 
+    // Mount 9p shares with optional overlayfs
+    ninep::mount_9p_shares(&config.ninep_mounts)?;
+
+    // Load init.envfile values now that their backing mounts are in place. init.env.* entries
+    // from the cmdline always win; among envfiles themselves, later files win.
+    if !config.envfiles.is_empty() {
+        let from_cmdline: std::collections::HashSet<String> =
+            config.env_vars.keys().cloned().collect();
+        for path in &config.envfiles {
+            let contents = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read init.envfile {}", path))?;
+            for (key, value) in cmdline::parse_envfile(&contents)? {
+                if !from_cmdline.contains(&key) {
+                    config.env_vars.insert(key, value);
+                }
+            }
+        }
+    }
+
+    // If a real root filesystem is configured, switch_root into it and exec its init instead
+    // of continuing into the throwaway initramfs shell below.
+    if let Some(root) = &config.switch_root {
+        println!("kdf-init: switching root to {}", root.device);
+        return switch_root::run(root);
+    }
+
     // TODO: Create symlinks
 
+    // Expand init.alias entries in the shell and script commands before running anything
+    config.shell = cmdline::expand_alias(config.shell, &config.aliases)?;
+    if let Some(script) = &mut config.script {
+        cmdline::expand_script_aliases(script, &config.aliases)?;
+    }
+
     // Set environment variables
     for (key, value) in &config.env_vars {
         println!("kdf-init: setting env var: {}={}", key, value);
@@ -55,6 +92,36 @@ fn run() -> Result<()> {
         std::env::set_current_dir(chdir)?;
     }
 
+    // Run provisioning steps (init.script / init.script.file) before the interactive shell
+    if let Some(script) = &config.script {
+        seq::run_script(script, config.user.as_ref())?;
+    }
+    let mut sequence = seq::Sequence::default();
+    if let Some(script_file) = &config.script_file {
+        let contents = std::fs::read_to_string(script_file)
+            .with_context(|| format!("Failed to read init.script.file {}", script_file))?;
+        sequence.steps.extend(seq::parse_script_file(&contents)?.steps);
+    }
+    if !sequence.steps.is_empty() {
+        let results = seq::run_sequence(&sequence, config.user.as_ref())?;
+        for result in &results {
+            match result.status {
+                Some(status) => println!(
+                    "kdf-init: step '{}' finished with status {:?}",
+                    result.display,
+                    status.code()
+                ),
+                None => println!("kdf-init: step '{}' left running", result.display),
+            }
+        }
+    }
+
+    // Start the control socket listener, if configured, before blocking on the interactive
+    // shell below.
+    if let Some(control_path) = &config.control {
+        control::spawn_listener(control_path)?;
+    }
+
     // Execute shell
     let (program, args) = &config.shell;
     let display_cmd = if args.is_empty() {
@@ -64,7 +131,7 @@ fn run() -> Result<()> {
     };
     println!("kdf-init: starting interactive shell: {}", display_cmd);
 
-    let exit_status = system::execute_shell(program, args, &config.console)?;
+    let exit_status = system::execute_shell(program, args, &config.console, config.user.as_ref())?;
 
     if exit_status.success() {
         println!("kdf-init: shell exited successfully");
@@ -75,11 +142,6 @@ fn run() -> Result<()> {
         );
     }
 
-    // TODO: Handle optional script execution
-    if config.script.is_some() {
-        eprintln!("kdf-init: init.script is not yet implemented");
-    }
-
     println!("kdf-init: initialization complete");
 
     // Shutdown the system
@@ -0,0 +1,58 @@
+//! 9p shared-folder mounts, parallel to [`crate::virtiofs`]'s virtiofs shares.
+
+use anyhow::{Context, Result};
+use rustix::mount::{mount, MountFlags};
+use std::path::PathBuf;
+
+use crate::cmdline::NinePMount;
+use crate::virtiofs::compose_overlay;
+
+/// Mount every configured 9p share. A mount with no overlay requested is mounted directly at
+/// its target path; `with_overlay` stages it at a private directory first and reuses
+/// [`compose_overlay`] (the same overlay-on-top logic virtiofs mounts use) to layer a writable
+/// tmpfs on top.
+pub fn mount_9p_shares(mounts: &[NinePMount]) -> Result<()> {
+    for (idx, m) in mounts.iter().enumerate() {
+        if m.with_overlay {
+            let staging = PathBuf::from(format!("/run/kdf-init/layers/9p-{}/0", idx));
+            std::fs::create_dir_all(&staging)
+                .with_context(|| format!("Failed to create {}", staging.display()))?;
+            mount_9p(m, &staging)?;
+            compose_overlay(&format!("9p-{}", idx), &[staging], &m.path)?;
+        } else {
+            std::fs::create_dir_all(&m.path)
+                .with_context(|| format!("Failed to create {}", m.path))?;
+            mount_9p(m, std::path::Path::new(&m.path))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn mount_9p(m: &NinePMount, target: &std::path::Path) -> Result<()> {
+    let mut opts = Vec::new();
+    if let Some(trans) = &m.trans {
+        opts.push(format!("trans={}", trans));
+    }
+    if let Some(version) = &m.version {
+        opts.push(format!("version={}", version));
+    }
+    if let Some(msize) = m.msize {
+        opts.push(format!("msize={}", msize));
+    }
+    if let Some(cache) = &m.cache {
+        opts.push(format!("cache={}", cache));
+    }
+    let data = opts.join(",");
+
+    mount(m.tag.as_str(), target, "9p", MountFlags::empty(), data.as_str())
+        .with_context(|| format!("Failed to mount 9p share '{}' at {}", m.tag, target.display()))?;
+
+    println!(
+        "kdf-init: mounted 9p share '{}' at {}",
+        m.tag,
+        target.display()
+    );
+
+    Ok(())
+}
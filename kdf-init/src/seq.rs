@@ -0,0 +1,356 @@
+//! Declarative oneshot/sequence runner for `init.script`/`init.script.file`: an ordered list of
+//! provisioning steps run before the interactive shell, modeled on rumia's sequential-operation
+//! executor.
+
+use anyhow::{Context, Result};
+
+use crate::cmdline::{Pipeline, PipelineJoin, Redirect, Script, UserSpec};
+
+/// Whether a step's process is waited on before moving to the next step, or left running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepMode {
+    /// Run to completion, then move on to the next step
+    Oneshot,
+    /// Spawn and leave running (e.g. a daemon), then immediately move on
+    StayRunning,
+}
+
+/// A single step in a provisioning sequence
+#[derive(Debug, Clone, PartialEq)]
+pub struct Step {
+    pub command: (String, Vec<String>),
+    pub env: Vec<(String, String)>,
+    /// If the step fails (oneshot, non-zero exit), keep running the sequence instead of
+    /// aborting it
+    pub ignore_failure: bool,
+    pub mode: StepMode,
+}
+
+/// An ordered list of provisioning steps
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Sequence {
+    pub steps: Vec<Step>,
+}
+
+/// Outcome of running one step
+#[derive(Debug)]
+pub struct StepResult {
+    pub display: String,
+    /// `None` for a `StayRunning` step, since there's no exit status to report yet
+    pub status: Option<std::process::ExitStatus>,
+}
+
+/// Run each step in order. A oneshot step that fails aborts the sequence unless
+/// `ignore_failure` is set on it; a `StayRunning` step is spawned and never blocks the
+/// sequence. Returns the results of every step; an unignored failure instead ends the
+/// sequence with an error naming that step and its exit status.
+///
+/// `user`, if given, is dropped to for every step, the same as the interactive shell (see
+/// [`crate::system::apply_user`]) — provisioning steps run with the same privileges the
+/// eventual shell would, rather than always as root.
+pub fn run_sequence(sequence: &Sequence, user: Option<&UserSpec>) -> Result<Vec<StepResult>> {
+    let mut results = Vec::with_capacity(sequence.steps.len());
+
+    for step in &sequence.steps {
+        let (program, args) = &step.command;
+        let display = if args.is_empty() {
+            program.clone()
+        } else {
+            format!("{} {}", program, args.join(" "))
+        };
+
+        println!("kdf-init: running step: {}", display);
+
+        let mut command = std::process::Command::new(program);
+        command.args(args);
+        for (key, value) in &step.env {
+            command.env(key, value);
+        }
+        if let Some(user) = user {
+            crate::system::apply_user(&mut command, user);
+        }
+
+        match step.mode {
+            StepMode::Oneshot => {
+                let status = command
+                    .status()
+                    .with_context(|| format!("Failed to run step '{}'", display))?;
+
+                if status.success() {
+                    println!("kdf-init: step '{}' succeeded", display);
+                } else if step.ignore_failure {
+                    eprintln!(
+                        "kdf-init: step '{}' failed with status {:?} (ignored)",
+                        display,
+                        status.code()
+                    );
+                } else {
+                    results.push(StepResult { display: display.clone(), status: Some(status) });
+                    anyhow::bail!("step '{}' failed with status {:?}", display, status.code());
+                }
+
+                results.push(StepResult { display, status: Some(status) });
+            }
+            StepMode::StayRunning => {
+                command
+                    .spawn()
+                    .with_context(|| format!("Failed to spawn step '{}'", display))?;
+                println!("kdf-init: step '{}' left running in the background", display);
+                results.push(StepResult { display, status: None });
+            }
+        }
+    }
+
+    Ok(results)
+}
+
+/// Run a parsed `init.script` value. Each pipeline runs in order: a `;` join always runs the
+/// next pipeline, `&&` only if the previous one succeeded, `||` only if it failed. Returns the
+/// exit status of the last pipeline that ran.
+///
+/// `user`, if given, is dropped to for every command, same as [`run_sequence`].
+pub fn run_script(script: &Script, user: Option<&UserSpec>) -> Result<std::process::ExitStatus> {
+    let mut last_status: Option<std::process::ExitStatus> = None;
+
+    for (join, pipeline) in &script.pipelines {
+        let should_run = match (join, last_status) {
+            (PipelineJoin::Sequential, _) => true,
+            (PipelineJoin::And, Some(status)) => status.success(),
+            (PipelineJoin::Or, Some(status)) => !status.success(),
+            (PipelineJoin::And | PipelineJoin::Or, None) => true,
+        };
+
+        if should_run {
+            last_status = Some(run_pipeline(pipeline, user)?);
+        }
+    }
+
+    last_status.context("init.script has no pipelines to run")
+}
+
+/// Run a single `|`-chained pipeline, wiring each command's stdout into the next one's stdin,
+/// and applying the first command's `<file` / last command's `>file`/`>>file` redirection if
+/// any. Returns the exit status of the pipeline's last command.
+fn run_pipeline(
+    pipeline: &Pipeline,
+    user: Option<&UserSpec>,
+) -> Result<std::process::ExitStatus> {
+    let display = pipeline_display(pipeline);
+    println!("kdf-init: running script pipeline: {}", display);
+
+    let last = pipeline.commands.len() - 1;
+    let mut children = Vec::with_capacity(pipeline.commands.len());
+    let mut next_stdin: Option<std::process::Stdio> = None;
+
+    for (i, cmd) in pipeline.commands.iter().enumerate() {
+        let mut command = std::process::Command::new(&cmd.program);
+        command.args(&cmd.args);
+        if let Some(user) = user {
+            crate::system::apply_user(&mut command, user);
+        }
+
+        if let Some(stdin) = next_stdin.take() {
+            command.stdin(stdin);
+        } else if let Some(path) = &cmd.stdin {
+            let file = std::fs::File::open(path)
+                .with_context(|| format!("Failed to open {} for reading", path))?;
+            command.stdin(file);
+        }
+
+        if i != last {
+            command.stdout(std::process::Stdio::piped());
+        } else if let Some(redirect) = &cmd.stdout {
+            command.stdout(open_redirect(redirect)?);
+        }
+
+        let mut child = command
+            .spawn()
+            .with_context(|| format!("Failed to run '{}' in pipeline: {}", cmd.program, display))?;
+        next_stdin = child.stdout.take().map(std::process::Stdio::from);
+        children.push(child);
+    }
+
+    let mut status = None;
+    for child in &mut children {
+        status = Some(
+            child
+                .wait()
+                .with_context(|| format!("Failed to wait on pipeline: {}", display))?,
+        );
+    }
+
+    status.context("pipeline has no commands")
+}
+
+fn open_redirect(redirect: &Redirect) -> Result<std::fs::File> {
+    match redirect {
+        Redirect::Truncate(path) => std::fs::File::create(path)
+            .with_context(|| format!("Failed to open {} for writing", path)),
+        Redirect::Append(path) => std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open {} for appending", path)),
+    }
+}
+
+fn pipeline_display(pipeline: &Pipeline) -> String {
+    pipeline
+        .commands
+        .iter()
+        .map(|c| {
+            if c.args.is_empty() {
+                c.program.clone()
+            } else {
+                format!("{} {}", c.program, c.args.join(" "))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" | ")
+}
+
+/// Parse a script file: one step per line, blank lines and `#` comments ignored. A line may
+/// start with flag characters before the command: `-` to ignore failure, `&` to leave the
+/// step running instead of waiting on it. Any `KEY=VALUE` tokens right before the command are
+/// taken as environment for that step only, e.g.:
+///
+/// ```text
+/// mkdir -p /mnt/data
+/// -rm -f /tmp/stale.lock
+/// &syslogd -n
+/// -FOO=bar BAZ=qux curl http://example.com
+/// ```
+pub fn parse_script_file(contents: &str) -> Result<Sequence> {
+    let mut steps = Vec::new();
+
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut ignore_failure = false;
+        let mut mode = StepMode::Oneshot;
+        let mut rest = line;
+        while let Some(c) = rest.chars().next() {
+            match c {
+                '-' => {
+                    ignore_failure = true;
+                    rest = &rest[1..];
+                }
+                '&' => {
+                    mode = StepMode::StayRunning;
+                    rest = &rest[1..];
+                }
+                _ => break,
+            }
+        }
+
+        let mut tokens = crate::cmdline::tokenize(rest.trim_start())
+            .with_context(|| format!("Invalid step: {}", line))?;
+        let env = take_leading_env_assignments(&mut tokens);
+        if tokens.is_empty() {
+            anyhow::bail!("Invalid step: {}", line);
+        }
+        let command = (tokens.remove(0), tokens);
+
+        steps.push(Step { command, env, ignore_failure, mode });
+    }
+
+    Ok(Sequence { steps })
+}
+
+/// Pull `KEY=VALUE` tokens off the front of `tokens` (stopping at the first token that isn't
+/// one), returning them as env pairs in order. `KEY` must look like a shell identifier
+/// (letters/digits/underscore, not starting with a digit), so a program name or argument that
+/// happens to contain `=` isn't mistaken for an assignment.
+fn take_leading_env_assignments(tokens: &mut Vec<String>) -> Vec<(String, String)> {
+    let mut env = Vec::new();
+    while let Some(token) = tokens.first() {
+        let Some((key, value)) = token.split_once('=') else { break };
+        if key.is_empty() || !is_env_key(key) {
+            break;
+        }
+        env.push((key.to_string(), value.to_string()));
+        tokens.remove(0);
+    }
+    env
+}
+
+fn is_env_key(key: &str) -> bool {
+    let mut chars = key.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_script_file_comments_and_blank_lines() {
+        let sequence = parse_script_file("# a comment\n\nmkdir -p /mnt/data\n").unwrap();
+        assert_eq!(sequence.steps.len(), 1);
+        assert_eq!(
+            sequence.steps[0].command,
+            ("mkdir".to_string(), vec!["-p".to_string(), "/mnt/data".to_string()])
+        );
+        assert!(!sequence.steps[0].ignore_failure);
+        assert_eq!(sequence.steps[0].mode, StepMode::Oneshot);
+    }
+
+    #[test]
+    fn test_parse_script_file_ignore_failure_prefix() {
+        let sequence = parse_script_file("-rm -f /tmp/stale.lock\n").unwrap();
+        assert_eq!(sequence.steps.len(), 1);
+        assert!(sequence.steps[0].ignore_failure);
+        assert_eq!(sequence.steps[0].mode, StepMode::Oneshot);
+    }
+
+    #[test]
+    fn test_parse_script_file_stay_running_prefix() {
+        let sequence = parse_script_file("&syslogd -n\n").unwrap();
+        assert_eq!(sequence.steps.len(), 1);
+        assert!(!sequence.steps[0].ignore_failure);
+        assert_eq!(sequence.steps[0].mode, StepMode::StayRunning);
+    }
+
+    #[test]
+    fn test_parse_script_file_combined_prefix_order() {
+        let sequence = parse_script_file("-&syslogd -n\n").unwrap();
+        assert_eq!(sequence.steps.len(), 1);
+        assert!(sequence.steps[0].ignore_failure);
+        assert_eq!(sequence.steps[0].mode, StepMode::StayRunning);
+    }
+
+    #[test]
+    fn test_parse_script_file_empty_after_prefix_errors() {
+        let result = parse_script_file("-&\n");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_script_file_env_assignments() {
+        let sequence = parse_script_file("-FOO=bar BAZ=qux curl http://example.com\n").unwrap();
+        assert_eq!(sequence.steps.len(), 1);
+        let step = &sequence.steps[0];
+        assert!(step.ignore_failure);
+        assert_eq!(
+            step.env,
+            vec![
+                ("FOO".to_string(), "bar".to_string()),
+                ("BAZ".to_string(), "qux".to_string()),
+            ]
+        );
+        assert_eq!(
+            step.command,
+            ("curl".to_string(), vec!["http://example.com".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_parse_script_file_no_env_assignments_defaults_empty() {
+        let sequence = parse_script_file("mkdir -p /mnt/data\n").unwrap();
+        assert!(sequence.steps[0].env.is_empty());
+    }
+}
@@ -0,0 +1,156 @@
+//! switch_root: mount a persistent root filesystem and hand off to its init, classic
+//! initramfs-to-real-root transition (see util-linux's switch_root(8)).
+
+use anyhow::{Context, Result};
+use rustix::fd::{AsFd, OwnedFd};
+use rustix::fs::{Mode, OFlags};
+use rustix::mount::{mount, mount_move, MountFlags};
+use rustix::process::{chdir, chroot};
+
+use crate::cmdline::SwitchRoot;
+
+const NEW_ROOT: &str = "/newroot";
+
+/// Filesystem types tried, in order, when `init.rootfstype` isn't given.
+const FSTYPE_GUESSES: &[&str] = &["ext4", "btrfs", "xfs", "vfat"];
+
+/// Kernel filesystems mounted earlier by [`crate::system::mount_kernel_filesystems`] that need
+/// to move into the new root before we chroot into it.
+const MOVE_MOUNTS: &[&str] = &["proc", "sys", "dev", "run"];
+
+/// Mount the real root described by `root`, move the kernel filesystems into it, switch_root,
+/// and exec `root.realinit`. On success this never returns (the process image is replaced).
+pub fn run(root: &SwitchRoot) -> Result<()> {
+    mkdir_p(NEW_ROOT)?;
+    mount_real_root(root)?;
+
+    for fs in MOVE_MOUNTS {
+        let old = format!("/{}", fs);
+        let new = format!("{}/{}", NEW_ROOT, fs);
+        mkdir_p(&new)?;
+        mount_move(old.as_str(), new.as_str())
+            .with_context(|| format!("Failed to move {} into new root", old))?;
+    }
+
+    // Keep a handle on the initramfs root so we can reclaim its space once we've chrooted
+    // away from it; once we move "." onto "/" below, this is the only remaining reference.
+    let old_root = rustix::fs::open("/", OFlags::RDONLY | OFlags::DIRECTORY, Mode::empty())
+        .context("Failed to open old root")?;
+
+    chdir(NEW_ROOT).context("Failed to chdir into new root")?;
+    mount_move(".", "/").context("Failed to move new root onto /")?;
+    chroot(".").context("Failed to chroot into new root")?;
+    chdir("/").context("Failed to chdir to / after chroot")?;
+
+    if let Err(e) = reclaim_old_root(old_root) {
+        eprintln!("kdf-init: warning: failed to clean up old root: {:?}", e);
+    }
+
+    println!("kdf-init: switch_root complete, exec'ing {}", root.realinit);
+    exec_realinit(&root.realinit)
+}
+
+fn mount_real_root(root: &SwitchRoot) -> Result<()> {
+    let (data, extra_flags) = split_mount_flags(&root.flags);
+
+    if let Some(fstype) = &root.fstype {
+        return mount(root.device.as_str(), NEW_ROOT, fstype.as_str(), extra_flags, data.as_str())
+            .with_context(|| format!("Failed to mount {} ({}) at {}", root.device, fstype, NEW_ROOT));
+    }
+
+    for fstype in FSTYPE_GUESSES {
+        if mount(root.device.as_str(), NEW_ROOT, *fstype, extra_flags, data.as_str()).is_ok() {
+            return Ok(());
+        }
+    }
+
+    anyhow::bail!(
+        "Failed to mount {} at {}: no init.rootfstype given and none of {:?} worked",
+        root.device,
+        NEW_ROOT,
+        FSTYPE_GUESSES
+    )
+}
+
+/// Split a comma-separated fstab-style flags string into the plain mount data string (for
+/// options the kernel filesystem driver interprets itself) and the `ro`/`rw` MountFlags bit.
+fn split_mount_flags(flags: &str) -> (String, MountFlags) {
+    let mut mount_flags = MountFlags::empty();
+    let data: Vec<&str> = flags
+        .split(',')
+        .filter(|opt| !opt.is_empty())
+        .filter(|opt| match *opt {
+            "ro" => {
+                mount_flags |= MountFlags::RDONLY;
+                false
+            }
+            "rw" => false,
+            _ => true,
+        })
+        .collect();
+
+    (data.join(","), mount_flags)
+}
+
+fn mkdir_p(path: &str) -> Result<()> {
+    rustix::fs::mkdir(path, Mode::from_raw_mode(0o755))
+        .or_else(|e| if e == rustix::io::Errno::EXIST { Ok(()) } else { Err(e) })
+        .with_context(|| format!("Failed to create {}", path))
+}
+
+/// Recursively delete everything still reachable through `old_root`, the initramfs we just
+/// left, so its tmpfs/ramfs memory is freed. Mirrors util-linux switch_root's "recursiveRemove":
+/// it is reached purely through the fd captured before the `MS_MOVE`/`chroot`, since by now no
+/// path in the new mount namespace leads back to it.
+fn reclaim_old_root(old_root: OwnedFd) -> Result<()> {
+    let dev = rustix::fs::fstat(old_root.as_fd())
+        .context("Failed to stat old root")?
+        .st_dev;
+    remove_contents(old_root.as_fd(), dev)
+}
+
+fn remove_contents(dir_fd: rustix::fd::BorrowedFd<'_>, root_dev: u64) -> Result<()> {
+    use rustix::fs::{unlinkat, AtFlags, FileType};
+
+    let dir = rustix::fs::Dir::read_from(dir_fd).context("Failed to read old root directory")?;
+
+    for entry in dir {
+        let entry = entry.context("Failed to read old root directory entry")?;
+        let name = entry.file_name();
+        if name.to_bytes() == b"." || name.to_bytes() == b".." {
+            continue;
+        }
+
+        let is_dir = entry.file_type() == FileType::Directory;
+
+        if is_dir {
+            let child_fd = rustix::fs::openat(
+                dir_fd,
+                name,
+                OFlags::RDONLY | OFlags::DIRECTORY | OFlags::NOFOLLOW,
+                Mode::empty(),
+            );
+            if let Ok(child_fd) = child_fd {
+                let child_dev = rustix::fs::fstat(child_fd.as_fd()).map(|st| st.st_dev).ok();
+                // Don't descend into (or delete) a filesystem other than the initramfs itself,
+                // e.g. something still bind-mounted under the old root.
+                if child_dev == Some(root_dev) {
+                    let _ = remove_contents(child_fd.as_fd(), root_dev);
+                    let _ = unlinkat(dir_fd, name, AtFlags::REMOVEDIR);
+                }
+            }
+        } else {
+            let _ = unlinkat(dir_fd, name, AtFlags::empty());
+        }
+    }
+
+    Ok(())
+}
+
+/// Exec the real init, replacing kdf-init. Returns only on failure.
+fn exec_realinit(realinit: &str) -> Result<()> {
+    use std::os::unix::process::CommandExt;
+
+    let err = std::process::Command::new(realinit).exec();
+    Err(anyhow::anyhow!("Failed to exec {}: {}", realinit, err))
+}
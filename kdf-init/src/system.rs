@@ -2,17 +2,19 @@
 
 use anyhow::{Context, Result};
 use rustix::fs::Mode;
-use rustix::mount::{mount, MountFlags};
+use rustix::mount::{mount, mount_change, MountFlags, MountPropagationFlags};
 
-struct KernelMount {
-    source: &'static str,
-    target: &'static str,
-    fstype: &'static str,
-    flags: MountFlags,
-    data: &'static str,
+use crate::cmdline::UserSpec;
+
+pub struct KernelMount {
+    pub source: &'static str,
+    pub target: &'static str,
+    pub fstype: &'static str,
+    pub flags: MountFlags,
+    pub data: &'static str,
 }
 
-const KERNEL_MOUNTS: &[KernelMount] = &[
+pub const DEFAULT_KERNEL_MOUNTS: &[KernelMount] = &[
     KernelMount {
         source: "proc",
         target: "/proc",
@@ -43,27 +45,128 @@ const KERNEL_MOUNTS: &[KernelMount] = &[
     },
 ];
 
-pub fn mount_kernel_filesystems() -> Result<()> {
-    for m in KERNEL_MOUNTS {
+/// Default mount propagation for the whole mount tree (rooted at "/") before mounting the
+/// kernel filesystems below, mirroring youki's `prepare_rootfs`: recursively slave, so the
+/// initramfs doesn't propagate its own mount/unmount events back out to the host.
+///
+/// `mount_kernel_filesystems_with` takes `MountPropagationFlags` directly (rather than a small
+/// wrapper enum) since today there is no cmdline key selecting anything other than this
+/// default — kernel cmdline parsing itself depends on `/proc` already being mounted here, so
+/// this particular choice can't be deferred to a parsed `Config`. Callers that do need a
+/// different propagation (e.g. nested-container scenarios) can still pass it directly.
+const DEFAULT_PROPAGATION: MountPropagationFlags =
+    MountPropagationFlags::REC.union(MountPropagationFlags::SLAVE);
+
+/// Mount the kernel filesystems in `mounts` (defaulting to [`DEFAULT_KERNEL_MOUNTS`]), after
+/// first setting the whole mount tree's propagation to `propagation` (defaulting to
+/// [`DEFAULT_PROPAGATION`]). When mounting `devtmpfs` fails (minimal kernels built without
+/// it), falls back to a tmpfs populated with [`populate_minimal_dev`].
+pub fn mount_kernel_filesystems_with(
+    propagation: Option<MountPropagationFlags>,
+    mounts: Option<&[KernelMount]>,
+) -> Result<()> {
+    mount_change("/", propagation.unwrap_or(DEFAULT_PROPAGATION))
+        .context("Failed to set rootfs mount propagation")?;
+
+    for m in mounts.unwrap_or(DEFAULT_KERNEL_MOUNTS) {
         // Create mount point if it doesn't exist
         rustix::fs::mkdir(m.target, Mode::from_raw_mode(0o755))
             .or_else(|e| if e == rustix::io::Errno::EXIST { Ok(()) } else { Err(e) })
             .with_context(|| format!("Failed to create {}", m.target))?;
 
         // Mount filesystem
-        mount(m.source, m.target, m.fstype, m.flags, m.data)
-            .with_context(|| format!("Failed to mount {}", m.target))?;
+        match mount(m.source, m.target, m.fstype, m.flags, m.data) {
+            Ok(()) => println!("kdf-init: mounted {}", m.target),
+            Err(_) if m.fstype == "devtmpfs" => {
+                println!(
+                    "kdf-init: devtmpfs unavailable, falling back to tmpfs + static nodes at {}",
+                    m.target
+                );
+                mount("tmpfs", m.target, "tmpfs", MountFlags::empty(), "mode=0755")
+                    .with_context(|| format!("Failed to mount tmpfs fallback at {}", m.target))?;
+                populate_minimal_dev(m.target)?;
+            }
+            Err(e) => return Err(e).with_context(|| format!("Failed to mount {}", m.target)),
+        }
+    }
+
+    Ok(())
+}
+
+/// Convenience wrapper for the common case: default propagation, default mount table.
+pub fn mount_kernel_filesystems() -> Result<()> {
+    mount_kernel_filesystems_with(None, None)
+}
 
-        println!("kdf-init: mounted {}", m.target);
+/// Populate a tmpfs mounted at `devdir` with the standard character devices and symlinks a
+/// devtmpfs would normally provide, for kernels without `CONFIG_DEVTMPFS`.
+fn populate_minimal_dev(devdir: &str) -> Result<()> {
+    use rustix::fs::{mknodat, symlinkat, FileType, CWD};
+
+    struct Node {
+        name: &'static str,
+        major: u32,
+        minor: u32,
+        mode: u32,
+    }
+
+    const NODES: &[Node] = &[
+        Node { name: "null", major: 1, minor: 3, mode: 0o666 },
+        Node { name: "zero", major: 1, minor: 5, mode: 0o666 },
+        Node { name: "full", major: 1, minor: 7, mode: 0o666 },
+        Node { name: "random", major: 1, minor: 8, mode: 0o666 },
+        Node { name: "urandom", major: 1, minor: 9, mode: 0o666 },
+        Node { name: "tty", major: 5, minor: 0, mode: 0o666 },
+        Node { name: "console", major: 5, minor: 1, mode: 0o600 },
+        Node { name: "ptmx", major: 5, minor: 2, mode: 0o666 },
+    ];
+
+    for node in NODES {
+        let path = format!("{}/{}", devdir, node.name);
+        mknodat(
+            CWD,
+            path.as_str(),
+            FileType::CharacterDevice,
+            Mode::from_raw_mode(node.mode),
+            rustix::fs::makedev(node.major, node.minor),
+        )
+        .with_context(|| format!("Failed to create device node {}", path))?;
+    }
+
+    const SYMLINKS: &[(&str, &str)] = &[
+        ("fd", "/proc/self/fd"),
+        ("stdin", "/proc/self/fd/0"),
+        ("stdout", "/proc/self/fd/1"),
+        ("stderr", "/proc/self/fd/2"),
+        ("core", "/proc/kcore"),
+    ];
+
+    for (name, target) in SYMLINKS {
+        let link = format!("{}/{}", devdir, name);
+        symlinkat(*target, CWD, link.as_str())
+            .with_context(|| format!("Failed to create symlink {}", link))?;
     }
 
+    println!("kdf-init: populated minimal /dev at {}", devdir);
+
     Ok(())
 }
 
-pub fn load_kernel_modules(modules_dir: Option<&str>) -> Result<()> {
-    use rustix::fd::AsFd;
-    use std::fs;
+/// Flag for `finit_module` telling the kernel the supplied file is compressed and should be
+/// decompressed in-kernel (requires `CONFIG_MODULE_DECOMPRESS`).
+const MODULE_INIT_COMPRESSED_FILE: i32 = 4;
+
+/// A single `.ko`/`.ko.xz`/`.ko.gz` module discovered under the moddir, along with the
+/// dependencies read from `modules.dep` (paths, not module names).
+struct ModuleEntry {
+    path: std::path::PathBuf,
+    deps: Vec<std::path::PathBuf>,
+}
 
+pub fn load_kernel_modules(
+    modules_dir: Option<&str>,
+    module_params: &std::collections::HashMap<String, String>,
+) -> Result<()> {
     // If no moddir specified, skip module loading
     let Some(modules_dir) = modules_dir else {
         println!("kdf-init: no moddir specified, skipping module loading");
@@ -78,51 +181,346 @@ pub fn load_kernel_modules(modules_dir: Option<&str>) -> Result<()> {
         return Ok(());
     }
 
-    // Read all files in modules directory
-    let entries = fs::read_dir(modules_dir)
-        .with_context(|| format!("Failed to read directory {}", modules_dir))?;
+    let moddir = std::path::Path::new(modules_dir);
+    let discovered = discover_module_files(moddir)?;
+    let deps = parse_modules_dep(moddir);
+    let order = parse_modules_order(moddir);
+
+    let mut entries: Vec<ModuleEntry> = discovered
+        .into_iter()
+        .map(|path| {
+            let dep_list = deps.get(&path).cloned().unwrap_or_default();
+            ModuleEntry { path, deps: dep_list }
+        })
+        .collect();
+
+    topo_sort_modules(&mut entries, &order);
 
+    let total_count = entries.len();
+    let mut pending = entries;
     let mut loaded_count = 0;
-    let mut failed_count = 0;
-    let mut total_count = 0;
+    let mut failed: Vec<(std::path::PathBuf, rustix::io::Errno)> = Vec::new();
+
+    loop {
+        let mut progressed = false;
+        let mut still_pending = Vec::new();
+
+        for entry in pending {
+            match load_one_module(&entry.path, module_params) {
+                Ok(()) => {
+                    loaded_count += 1;
+                    progressed = true;
+                }
+                // Unresolved symbol / missing dependency: the module that provides it may
+                // not have loaded yet. Defer to the next sweep.
+                Err(e) if e == rustix::io::Errno::NOENT || e == rustix::io::Errno::AGAIN => {
+                    still_pending.push(entry);
+                }
+                Err(e) => {
+                    println!(
+                        "kdf-init: failed to load {}: {} (errno: {:?})",
+                        entry.path.display(),
+                        e,
+                        e
+                    );
+                    failed.push((entry.path, e));
+                }
+            }
+        }
+
+        pending = still_pending;
+
+        if pending.is_empty() || !progressed {
+            break;
+        }
+    }
+
+    for entry in &pending {
+        println!(
+            "kdf-init: giving up on {}: dependency never became available",
+            entry.path.display()
+        );
+    }
+    let failed_count = failed.len() + pending.len();
+
+    println!(
+        "kdf-init: module loading complete: {} loaded, {} failed, {} total",
+        loaded_count, failed_count, total_count
+    );
+
+    Ok(())
+}
+
+/// Recursively find `.ko`/`.ko.xz`/`.ko.gz` files under `dir`.
+fn discover_module_files(dir: &std::path::Path) -> Result<Vec<std::path::PathBuf>> {
+    let mut modules = Vec::new();
+    let entries = std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to read directory {}", dir.display()))?;
 
     for entry in entries {
         let entry = entry.context("Failed to read directory entry")?;
         let path = entry.path();
 
-        // Only process .ko files (including compressed ones)
-        if let Some(ext) = path.extension() {
-            let ext_str = ext.to_string_lossy();
-            if ext_str == "ko" || path.to_string_lossy().ends_with(".ko.xz") || path.to_string_lossy().ends_with(".ko.gz") {
-                total_count += 1;
-                let file_name = path.file_name().unwrap().to_string_lossy();
-                println!("kdf-init: loading module {}", file_name);
-
-                match fs::File::open(&path) {
-                    Ok(file) => {
-                        use std::ffi::CStr;
-                        let empty_params = CStr::from_bytes_with_nul(b"\0").unwrap();
-                        match rustix::system::finit_module(file.as_fd(), empty_params, 0) {
-                            Ok(_) => {
-                                println!("kdf-init: successfully loaded {}", file_name);
-                                loaded_count += 1;
-                            }
-                            Err(e) => {
-                                println!("kdf-init: failed to load {}: {} (errno: {:?})", file_name, e, e);
-                                failed_count += 1;
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        println!("kdf-init: failed to open {}: {}", file_name, e);
-                        failed_count += 1;
-                    }
-                }
+        if path.is_dir() {
+            modules.extend(discover_module_files(&path)?);
+            continue;
+        }
+
+        let name = path.to_string_lossy();
+        if name.ends_with(".ko") || name.ends_with(".ko.xz") || name.ends_with(".ko.gz") {
+            modules.push(path);
+        }
+    }
+
+    Ok(modules)
+}
+
+/// Parse `modules.dep` (depmod format: `path: dep1 dep2 ...`) into a map from module path to
+/// its dependency paths, both resolved relative to `moddir`.
+fn parse_modules_dep(
+    moddir: &std::path::Path,
+) -> std::collections::HashMap<std::path::PathBuf, Vec<std::path::PathBuf>> {
+    let mut graph = std::collections::HashMap::new();
+
+    let Ok(contents) = std::fs::read_to_string(moddir.join("modules.dep")) else {
+        return graph;
+    };
+
+    for line in contents.lines() {
+        let Some((module, deps)) = line.split_once(':') else {
+            continue;
+        };
+
+        let key = moddir.join(module.trim());
+        let values = deps
+            .split_whitespace()
+            .map(|dep| moddir.join(dep))
+            .collect();
+
+        graph.insert(key, values);
+    }
+
+    graph
+}
+
+/// Parse `modules.order` (one relative module path per line) into a preferred load order.
+fn parse_modules_order(moddir: &std::path::Path) -> Vec<std::path::PathBuf> {
+    std::fs::read_to_string(moddir.join("modules.order"))
+        .map(|contents| {
+            contents
+                .lines()
+                .filter(|l| !l.is_empty())
+                .map(|l| moddir.join(l.trim()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Order `entries` so dependencies come before dependents, using `modules.dep` as the graph
+/// and `preferred_order` (from `modules.order`) to break ties. The graph from `modules.dep`
+/// may be incomplete or reference modules outside of `entries`, and may contain cycles; on
+/// a cycle the dependency edge that closes it is skipped and ordering carries on as normal.
+/// Any remaining unresolved dependencies are handled by the fixpoint retry loop in the caller.
+fn topo_sort_modules(entries: &mut Vec<ModuleEntry>, preferred_order: &[std::path::PathBuf]) {
+    let index_of = |path: &std::path::Path| preferred_order.iter().position(|p| p == path);
+    entries.sort_by_key(|e| index_of(&e.path).unwrap_or(usize::MAX));
+
+    let by_path: std::collections::HashMap<_, _> = entries
+        .iter()
+        .enumerate()
+        .map(|(i, e)| (e.path.clone(), i))
+        .collect();
+
+    let mut visited = vec![false; entries.len()];
+    let mut visiting = vec![false; entries.len()];
+    let mut sorted = Vec::with_capacity(entries.len());
+
+    fn visit(
+        i: usize,
+        entries: &[ModuleEntry],
+        by_path: &std::collections::HashMap<std::path::PathBuf, usize>,
+        visited: &mut Vec<bool>,
+        visiting: &mut Vec<bool>,
+        sorted: &mut Vec<usize>,
+    ) {
+        if visited[i] || visiting[i] {
+            // Either already placed, or we've hit a cycle: bail out of this branch.
+            return;
+        }
+        visiting[i] = true;
+        for dep in &entries[i].deps {
+            if let Some(&j) = by_path.get(dep) {
+                visit(j, entries, by_path, visited, visiting, sorted);
             }
         }
+        visiting[i] = false;
+        visited[i] = true;
+        sorted.push(i);
+    }
+
+    for i in 0..entries.len() {
+        visit(i, entries, &by_path, &mut visited, &mut visiting, &mut sorted);
+    }
+
+    let mut ordered = Vec::with_capacity(entries.len());
+    for i in sorted {
+        ordered.push(std::mem::replace(
+            &mut entries[i],
+            ModuleEntry { path: std::path::PathBuf::new(), deps: Vec::new() },
+        ));
+    }
+    *entries = ordered;
+}
+
+/// Load a single module, passing per-module parameters if configured and requesting
+/// in-kernel decompression for `.ko.xz`/`.ko.gz`, falling back to userspace decompression
+/// when the kernel doesn't support `CONFIG_MODULE_DECOMPRESS`.
+fn load_one_module(
+    path: &std::path::Path,
+    module_params: &std::collections::HashMap<String, String>,
+) -> std::result::Result<(), rustix::io::Errno> {
+    use rustix::fd::AsFd;
+    use std::ffi::CString;
+
+    let name = path.to_string_lossy();
+    let stem = module_stem(path);
+    let params = module_params
+        .get(stem)
+        .map(|s| s.as_str())
+        .unwrap_or("");
+    let params = CString::new(params).unwrap_or_default();
+
+    let compressed = name.ends_with(".ko.xz") || name.ends_with(".ko.gz");
+    let flags = if compressed { MODULE_INIT_COMPRESSED_FILE } else { 0 };
+
+    let file = std::fs::File::open(path).map_err(|e| rustix::io::Errno::from_io_error(&e).unwrap_or(rustix::io::Errno::IO))?;
+
+    println!("kdf-init: loading module {}", name);
+    match rustix::system::finit_module(file.as_fd(), &params, flags) {
+        Ok(()) => {
+            println!("kdf-init: successfully loaded {}", name);
+            Ok(())
+        }
+        // Kernel can't decompress in-kernel (EOPNOTSUPP without CONFIG_MODULE_DECOMPRESS,
+        // EINVAL on kernels that predate the flag): fall back to decompressing ourselves.
+        Err(rustix::io::Errno::OPNOTSUPP | rustix::io::Errno::INVAL) if compressed => {
+            let decompressed = decompress_module(path)
+                .map_err(|_| rustix::io::Errno::NOEXEC)?;
+            let file = std::fs::File::open(&decompressed)
+                .map_err(|e| rustix::io::Errno::from_io_error(&e).unwrap_or(rustix::io::Errno::IO))?;
+            let result = rustix::system::finit_module(file.as_fd(), &params, 0);
+            let _ = std::fs::remove_file(&decompressed);
+            result.map(|_| {
+                println!("kdf-init: successfully loaded {} (userspace decompressed)", name);
+            })
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Module basename with the `.ko`/`.ko.xz`/`.ko.gz` extension stripped, e.g. "e1000e".
+fn module_stem(path: &std::path::Path) -> &str {
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    name.strip_suffix(".ko.xz")
+        .or_else(|| name.strip_suffix(".ko.gz"))
+        .or_else(|| name.strip_suffix(".ko"))
+        .unwrap_or(name)
+}
+
+/// Decompress a `.ko.xz`/`.ko.gz` module to a temporary file, for kernels built without
+/// `CONFIG_MODULE_DECOMPRESS`.
+fn decompress_module(path: &std::path::Path) -> Result<std::path::PathBuf> {
+    let name = path.to_string_lossy();
+    let dest = std::path::PathBuf::from(format!(
+        "/tmp/{}.decompressed",
+        path.file_name().unwrap().to_string_lossy()
+    ));
+
+    let input = std::fs::File::open(path)
+        .with_context(|| format!("Failed to open {} for decompression", name))?;
+    let mut out = std::fs::File::create(&dest)
+        .with_context(|| format!("Failed to create {}", dest.display()))?;
+
+    if name.ends_with(".ko.xz") {
+        let mut decoder = xz2::read::XzDecoder::new(input);
+        std::io::copy(&mut decoder, &mut out)
+            .with_context(|| format!("Failed to decompress {}", name))?;
+    } else {
+        let mut decoder = flate2::read::GzDecoder::new(input);
+        std::io::copy(&mut decoder, &mut out)
+            .with_context(|| format!("Failed to decompress {}", name))?;
+    }
+
+    Ok(dest)
+}
+
+/// Execute the interactive shell, optionally dropping privileges
+/// to the user described by `user` first.
+///
+/// kdf-init itself keeps running as PID 1/root so it can reap the child and power off the
+/// system afterwards; only the spawned process has its privileges dropped, via `pre_exec`
+/// running setgroups/setgid/setuid between fork and exec.
+pub fn execute_shell(
+    program: &str,
+    args: &[String],
+    console: &str,
+    user: Option<&UserSpec>,
+) -> Result<std::process::ExitStatus> {
+    use std::process::Command;
+
+    let mut command = Command::new(program);
+    command.args(args);
+
+    if let Some(user) = user {
+        apply_user(&mut command, user);
     }
 
-    println!("kdf-init: module loading complete: {} loaded, {} failed, {} total", loaded_count, failed_count, total_count);
+    println!(
+        "kdf-init: launching on console {} as {}",
+        console,
+        user.map(|u| u.uid.to_string())
+            .unwrap_or_else(|| "root".to_string())
+    );
+
+    command.status().context("Failed to execute shell")
+}
+
+/// Set up `command` to run as `user` once spawned: `HOME`/`USER`/`LOGNAME` in its environment,
+/// and a `pre_exec` hook dropping root to `user`'s uid/gid/groups between fork and exec. Shared
+/// by [`execute_shell`] (the interactive shell) and [`crate::seq`] (provisioning steps), so
+/// `init.uid`/`init.gid`/`init.user`/`init.groups` apply the same way to both.
+pub fn apply_user(command: &mut std::process::Command, user: &UserSpec) {
+    command.env("HOME", &user.home);
+    if let Some(name) = &user.user {
+        command.env("USER", name);
+        command.env("LOGNAME", name);
+    }
+
+    let user = user.clone();
+    // Safety: setgroups/setgid/setuid are async-signal-safe and only touch the child's
+    // own credentials between fork and exec.
+    unsafe {
+        use std::os::unix::process::CommandExt;
+        command.pre_exec(move || drop_privileges(&user).map_err(std::io::Error::from));
+    }
+}
+
+/// Drop from root to the uid/gid/supplementary-groups described by `user`, in the correct
+/// order: setgroups, then setgid, then setuid (uid is dropped last since it revokes the
+/// privilege needed for the earlier two calls).
+fn drop_privileges(user: &UserSpec) -> rustix::io::Result<()> {
+    use rustix::process::{Gid, Uid};
+    use rustix::thread::{set_thread_gid, set_thread_groups, set_thread_uid};
+
+    let groups: Vec<Gid> = user
+        .groups
+        .iter()
+        .map(|&g| unsafe { Gid::from_raw(g) })
+        .collect();
+    set_thread_groups(&groups)?;
+
+    set_thread_gid(unsafe { Gid::from_raw(user.gid) })?;
+    set_thread_uid(unsafe { Uid::from_raw(user.uid) })?;
 
     Ok(())
 }
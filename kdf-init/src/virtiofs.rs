@@ -0,0 +1,205 @@
+//! Virtiofs share mounting, including multi-layer overlayfs composition
+
+use anyhow::{Context, Result};
+use rustix::mount::{mount, MountFlags};
+use std::path::{Path, PathBuf};
+
+use crate::cmdline::{LowerLayer, VirtiofsMount};
+
+/// Mount every configured virtiofs share. A mount with a single virtiofs layer and no
+/// overlay requested is mounted directly, same as before; anything else (multiple layers,
+/// squashfs layers, or `with_overlay`) is composed into an overlayfs via [`mount_layered`].
+pub fn mount_virtiofs_shares(mounts: &[VirtiofsMount]) -> Result<()> {
+    for (idx, m) in mounts.iter().enumerate() {
+        if let [LowerLayer::Virtiofs(tag)] = m.layers.as_slice() {
+            if !m.with_overlay {
+                create_dir_all(&m.path)?;
+                mount(tag.as_str(), m.path.as_str(), "virtiofs", MountFlags::empty(), "")
+                    .with_context(|| {
+                        format!("Failed to mount virtiofs share '{}' at {}", tag, m.path)
+                    })?;
+                println!("kdf-init: mounted virtiofs share '{}' at {}", tag, m.path);
+                continue;
+            }
+        }
+
+        mount_layered(&format!("virtiofs-{}", idx), &m.layers, &m.path)?;
+    }
+
+    Ok(())
+}
+
+/// Stage each lower layer (virtiofs tag or loop-mounted squashfs image) into its own private
+/// directory under `/run/kdf-init/layers/<group>`, then compose them into a single overlayfs
+/// mount at `path` with a writable tmpfs upper/work dir. `layers` is in priority order, highest
+/// first, matching overlayfs' `lowerdir=` semantics (earlier entries shadow later ones).
+///
+/// `group` only needs to be unique per call; it just keeps each mount's staging directories
+/// from colliding with another's. Callers that share this staging namespace with another mount
+/// type (e.g. [`crate::ninep`]'s 9p shares) must key it with a type-specific prefix, not a bare
+/// index, since two different mount lists can otherwise reuse the same index.
+pub fn mount_layered(group: &str, layers: &[LowerLayer], path: &str) -> Result<()> {
+    if layers.is_empty() {
+        anyhow::bail!("mount at {} has no lower layers configured", path);
+    }
+
+    let staging_root = PathBuf::from(format!("/run/kdf-init/layers/{}", group));
+    let mut lowerdirs = Vec::with_capacity(layers.len());
+
+    for (i, layer) in layers.iter().enumerate() {
+        let staging = staging_root.join(i.to_string());
+        create_dir_all_path(&staging)?;
+
+        match layer {
+            LowerLayer::Virtiofs(tag) => {
+                mount(tag.as_str(), &staging, "virtiofs", MountFlags::empty(), "")
+                    .with_context(|| format!("Failed to mount virtiofs share '{}'", tag))?;
+            }
+            LowerLayer::Squashfs(image) => {
+                mount_squashfs_image(image, &staging)?;
+            }
+        }
+
+        lowerdirs.push(staging);
+    }
+
+    compose_overlay(group, &lowerdirs, path)
+}
+
+/// Compose already-mounted `lowerdirs` (highest priority first) into a single overlayfs mount
+/// at `path`, with a writable tmpfs backing the upper/work dirs. Shared by [`mount_layered`]
+/// and any other caller that has already staged its own lower layers (e.g. 9p shares wanting
+/// an overlay on top of a single mount). See [`mount_layered`] on picking a `group` key that
+/// won't collide with another mount type's.
+pub fn compose_overlay(group: &str, lowerdirs: &[PathBuf], path: &str) -> Result<()> {
+    if lowerdirs.is_empty() {
+        anyhow::bail!("mount at {} has no lower layers configured", path);
+    }
+
+    let rw = PathBuf::from(format!("/run/kdf-init/layers/{}/rw", group));
+    create_dir_all_path(&rw)?;
+    mount("tmpfs", &rw, "tmpfs", MountFlags::empty(), "mode=0755")
+        .with_context(|| format!("Failed to mount tmpfs upper for {}", path))?;
+
+    let upper = rw.join("upper");
+    let work = rw.join("work");
+    create_dir_all_path(&upper)?;
+    create_dir_all_path(&work)?;
+
+    create_dir_all(path)?;
+
+    let lowerdir = lowerdirs
+        .iter()
+        .map(|p| p.to_string_lossy().to_string())
+        .collect::<Vec<_>>()
+        .join(":");
+    let data = format!(
+        "lowerdir={},upperdir={},workdir={}",
+        lowerdir,
+        upper.display(),
+        work.display()
+    );
+
+    mount("overlay", path, "overlay", MountFlags::empty(), data.as_str())
+        .with_context(|| format!("Failed to mount overlayfs at {}", path))?;
+
+    println!(
+        "kdf-init: mounted {}-layer overlay at {} (lowerdir={})",
+        lowerdirs.len(),
+        path,
+        lowerdir
+    );
+
+    Ok(())
+}
+
+/// Loop-mount a squashfs image read-only at `target`.
+fn mount_squashfs_image(image: &str, target: &Path) -> Result<()> {
+    use rustix::fd::AsFd;
+
+    let file = std::fs::File::open(image)
+        .with_context(|| format!("Failed to open squashfs image {}", image))?;
+    let loop_dev = loopdev::attach(file.as_fd())
+        .with_context(|| format!("Failed to attach loop device for {}", image))?;
+
+    mount(
+        loop_dev.to_string_lossy().as_ref(),
+        target,
+        "squashfs",
+        MountFlags::RDONLY,
+        "",
+    )
+    .with_context(|| format!("Failed to mount squashfs image {} at {}", image, target.display()))?;
+
+    println!(
+        "kdf-init: mounted squashfs image {} via {} at {}",
+        image,
+        loop_dev.display(),
+        target.display()
+    );
+
+    Ok(())
+}
+
+fn create_dir_all(path: &str) -> Result<()> {
+    std::fs::create_dir_all(path).with_context(|| format!("Failed to create {}", path))
+}
+
+fn create_dir_all_path(path: &Path) -> Result<()> {
+    std::fs::create_dir_all(path).with_context(|| format!("Failed to create {}", path.display()))
+}
+
+/// Minimal loop-device attachment via the kernel's loop-control interface (`LOOP_CTL_GET_FREE`,
+/// `LOOP_SET_FD`), used to mount squashfs image files without a `losetup` binary available in
+/// the initramfs.
+mod loopdev {
+    use anyhow::{Context, Result};
+    use rustix::fd::{AsFd, AsRawFd, BorrowedFd};
+    use rustix::ioctl::{ioctl, Ioctl, IoctlOutput, Opcode, Setter, WriteOpcode};
+    use std::ffi::c_void;
+    use std::path::PathBuf;
+
+    /// `LOOP_CTL_GET_FREE`: unlike the usual "write result into the pointed-to buffer" ioctls,
+    /// the free loop device index is the ioctl's own return value, not data written through the
+    /// (here, unused/null) pointer argument. [`NoArg`](rustix::ioctl::NoArg) always yields `()`
+    /// regardless of its type parameter, so this needs its own `Ioctl` impl to surface that
+    /// return value.
+    struct LoopCtlGetFree;
+
+    unsafe impl Ioctl for LoopCtlGetFree {
+        type Output = i32;
+
+        const OPCODE: Opcode = Opcode::none::<()>(0x4C, 0x82);
+        const IS_MUTATING: bool = false;
+
+        fn as_ptr(&mut self) -> *mut c_void {
+            std::ptr::null_mut()
+        }
+
+        unsafe fn output_from_ptr(out: IoctlOutput, _: *mut c_void) -> rustix::io::Result<i32> {
+            Ok(out)
+        }
+    }
+
+    type LoopSetFd = Setter<WriteOpcode<0x4C, 0x00, i32>, i32>;
+
+    /// Find a free loop device, bind `image_fd` to it, and return its path (e.g. "/dev/loop0").
+    pub fn attach(image_fd: BorrowedFd<'_>) -> Result<PathBuf> {
+        let ctl = std::fs::File::open("/dev/loop-control")
+            .context("Failed to open /dev/loop-control")?;
+        let index: i32 = unsafe { ioctl(ctl.as_fd(), LoopCtlGetFree) }
+            .context("LOOP_CTL_GET_FREE failed")?;
+
+        let loop_path = PathBuf::from(format!("/dev/loop{}", index));
+        let loop_dev = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&loop_path)
+            .with_context(|| format!("Failed to open {}", loop_path.display()))?;
+
+        unsafe { ioctl(loop_dev.as_fd(), LoopSetFd::new(image_fd.as_raw_fd())) }
+            .with_context(|| format!("LOOP_SET_FD failed for {}", loop_path.display()))?;
+
+        Ok(loop_path)
+    }
+}